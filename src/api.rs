@@ -0,0 +1,484 @@
+use crate::error::GptError;
+use clap::Args;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::AddAssign;
+use std::str::FromStr;
+
+pub const DEFAULT_TEMPERATURE: f64 = 0.3;
+pub const DEFAULT_MAX_TOKENS: usize = 50;
+pub const DEFAULT_N: u8 = 1;
+/// Most stop sequences the API accepts
+pub const MAX_STOP: usize = 4;
+/// Most alternatives per token the completions endpoint returns
+pub const MAX_LOGPROBS: u8 = 5;
+/// Most alternatives per token the chat completions endpoint returns
+pub const MAX_CHAT_LOGPROBS: u8 = 20;
+
+/// Bias added to one token's logit, parsed from `TOKEN=BIAS`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogitBias {
+	pub token: u32,
+	pub bias: f64,
+}
+
+impl FromStr for LogitBias {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (token, bias) = s
+			.split_once('=')
+			.ok_or_else(|| format!("expected TOKEN=BIAS, got `{}`", s))?;
+		Ok(Self {
+			token: token
+				.trim()
+				.parse()
+				.map_err(|e| format!("invalid token id `{}`: {}", token, e))?,
+			bias: bias
+				.trim()
+				.parse()
+				.map_err(|e| format!("invalid bias `{}`: {}", bias, e))?,
+		})
+	}
+}
+
+/// The API takes logit biases as a map from token id to bias
+fn serialize_logit_bias<S: Serializer>(biases: &[LogitBias], serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.collect_map(biases.iter().map(|b| (b.token.to_string(), b.bias)))
+}
+
+fn deserialize_logit_bias<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<LogitBias>, D::Error> {
+	let map: HashMap<String, f64> = serde::Deserialize::deserialize(deserializer)?;
+	map.into_iter()
+		.map(|(token, bias)| {
+			let token = token.parse().map_err(serde::de::Error::custom)?;
+			Ok(LogitBias { token, bias })
+		})
+		.collect()
+}
+
+// Settings of a completions request, also saved with sessions
+#[derive(Debug, Clone, Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GptRequest {
+	/// Model to use, defaults depend on the endpoint
+	#[clap(long, env = "GPT_MODEL")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub model: Option<String>,
+	/// Prompt for GPT
+	#[clap(short = 'P', long, default_value = "")]
+	#[serde(default)]
+	pub prompt: String,
+	/// Response Temperature [default: 0.3]
+	#[clap(short, long, env = "GPT_TEMPERATURE")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub temperature: Option<f64>,
+	/// Max tokens to use [default: 50]
+	#[clap(short, long, env = "GPT_MAX_TOKENS")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_tokens: Option<usize>,
+	/// How Many Responses to generate [default: 1]
+	#[clap(short, long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub n: Option<u8>,
+	/// Sequence where generation stops, up to 4
+	#[clap(short, long, env = "GPT_STOP", multiple_occurrences = true)]
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub stop: Vec<String>,
+	/// Nucleus sampling, only tokens in the top_p probability mass are considered
+	#[clap(long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub top_p: Option<f64>,
+	/// Penalise tokens already present, from -2.0 to 2.0
+	#[clap(long, allow_hyphen_values = true)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub presence_penalty: Option<f64>,
+	/// Penalise tokens by how often they appear, from -2.0 to 2.0
+	#[clap(long, allow_hyphen_values = true)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub frequency_penalty: Option<f64>,
+	/// Bias a token id, from -100 to 100, as TOKEN=BIAS
+	#[clap(long, multiple_occurrences = true, allow_hyphen_values = true)]
+	#[serde(
+		default,
+		skip_serializing_if = "Vec::is_empty",
+		serialize_with = "serialize_logit_bias",
+		deserialize_with = "deserialize_logit_bias"
+	)]
+	pub logit_bias: Vec<LogitBias>,
+	/// Generate this many completions and return the best n (completions only)
+	#[clap(long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub best_of: Option<u8>,
+	/// Echo the prompt back with the completion (completions only)
+	#[clap(long)]
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub echo: bool,
+	/// Text that comes after the completion (completions only)
+	#[clap(long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub suffix: Option<String>,
+	/// End user id passed on for abuse monitoring
+	#[clap(long, env = "GPT_USER")]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub user: Option<String>,
+	/// Seed for best effort deterministic sampling
+	#[clap(long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seed: Option<i64>,
+	/// Return log probabilities with this many top alternatives per token
+	#[clap(long)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub logprobs: Option<u8>,
+	/// Print tokens as they are generated
+	#[clap(long)]
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub stream: bool,
+}
+
+impl GptRequest {
+	/// Fills settings left unset by the command line, env and profile
+	pub fn apply_defaults(&mut self) {
+		self.temperature.get_or_insert(DEFAULT_TEMPERATURE);
+		self.max_tokens.get_or_insert(DEFAULT_MAX_TOKENS);
+		self.n.get_or_insert(DEFAULT_N);
+	}
+
+	/// Most tokens the response can contain over all choices, best_of are all billed
+	pub fn max_completion_tokens(&self) -> u64 {
+		let max_tokens = self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) as u64;
+		let n = self.n.unwrap_or(DEFAULT_N).max(self.best_of.unwrap_or(0));
+		max_tokens * n as u64
+	}
+
+	/// Checks settings are in the ranges the API accepts, so bad ones fail before sending
+	pub fn validate(&self, chat: bool) -> Result<(), GptError> {
+		let invalid = |message: String| Err(GptError::Config(message));
+		let in_range = |name: &str, value: Option<f64>, min: f64, max: f64| match value {
+			Some(value) if !(min..=max).contains(&value) => {
+				invalid(format!("{} must be between {} and {}, got {}", name, min, max, value))
+			}
+			_ => Ok(()),
+		};
+		in_range("temperature", self.temperature, 0.0, 2.0)?;
+		in_range("top_p", self.top_p, 0.0, 1.0)?;
+		in_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+		in_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+		for bias in &self.logit_bias {
+			in_range("logit_bias", Some(bias.bias), -100.0, 100.0)?;
+		}
+		if self.max_tokens == Some(0) {
+			return invalid(String::from("max_tokens must be at least 1"));
+		}
+		if self.n == Some(0) {
+			return invalid(String::from("n must be at least 1"));
+		}
+		if self.stop.len() > MAX_STOP {
+			return invalid(format!(
+				"At most {} stop sequences are allowed, got {}",
+				MAX_STOP,
+				self.stop.len()
+			));
+		}
+		if self.stop.iter().any(String::is_empty) {
+			return invalid(String::from("Stop sequences can't be empty"));
+		}
+		let max_logprobs = if chat { MAX_CHAT_LOGPROBS } else { MAX_LOGPROBS };
+		if self.logprobs.is_some_and(|n| n > max_logprobs) {
+			return invalid(format!("logprobs must be at most {}", max_logprobs));
+		}
+		if chat {
+			let completion_only = [
+				("best_of", self.best_of.is_some()),
+				("echo", self.echo),
+				("suffix", self.suffix.is_some()),
+			];
+			if let Some((name, _)) = completion_only.iter().find(|(_, set)| *set) {
+				return invalid(format!("{} isn't supported by the chat endpoint", name));
+			}
+		}
+		if let Some(best_of) = self.best_of {
+			let n = self.n.unwrap_or(DEFAULT_N);
+			if best_of < n {
+				return invalid(format!("best_of ({}) must be at least n ({})", best_of, n));
+			}
+			if self.stream {
+				return invalid(String::from("best_of can't be used with --stream"));
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	System,
+	User,
+	Assistant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+	pub role: Role,
+	pub content: String,
+}
+
+impl ChatMessage {
+	pub fn new(role: Role, content: impl Into<String>) -> Self {
+		Self {
+			role,
+			content: content.into(),
+		}
+	}
+}
+
+/// Request body for the chat completions endpoint
+#[derive(Debug, Serialize)]
+pub struct ChatRequest {
+	pub model: String,
+	pub messages: Vec<ChatMessage>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub temperature: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_tokens: Option<usize>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub n: Option<u8>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub stop: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub top_p: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub presence_penalty: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub frequency_penalty: Option<f64>,
+	#[serde(
+		skip_serializing_if = "Vec::is_empty",
+		serialize_with = "serialize_logit_bias"
+	)]
+	pub logit_bias: Vec<LogitBias>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub user: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seed: Option<i64>,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub logprobs: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub top_logprobs: Option<u8>,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub stream: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub stream_options: Option<StreamOptions>,
+}
+
+/// Asks for a final streamed chunk carrying the token usage
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StreamOptions {
+	pub include_usage: bool,
+}
+
+impl ChatRequest {
+	/// Builds a chat request carrying the model and sampling settings of `request`
+	pub fn new(messages: Vec<ChatMessage>, request: &GptRequest) -> Self {
+		Self {
+			model: request.model.clone().unwrap_or_default(),
+			messages,
+			temperature: request.temperature,
+			max_tokens: request.max_tokens,
+			n: request.n,
+			stop: request.stop.clone(),
+			top_p: request.top_p,
+			presence_penalty: request.presence_penalty,
+			frequency_penalty: request.frequency_penalty,
+			logit_bias: request.logit_bias.clone(),
+			user: request.user.clone(),
+			seed: request.seed,
+			logprobs: request.logprobs.is_some(),
+			top_logprobs: request.logprobs.filter(|&n| n > 0),
+			stream: request.stream,
+			stream_options: request.stream.then_some(StreamOptions {
+				include_usage: true,
+			}),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GptChoice {
+	/// Completion text, set by the completions endpoint
+	pub text: Option<String>,
+	/// Completion message, set by the chat completions endpoint
+	pub message: Option<ChatMessage>,
+	pub index: u8,
+	pub finish_reason: Option<String>,
+	/// Set when the request asked for `logprobs`
+	pub logprobs: Option<Logprobs>,
+}
+
+impl GptChoice {
+	/// Generated text regardless of which endpoint produced it
+	pub fn content(&self) -> &str {
+		match (&self.text, &self.message) {
+			(Some(text), _) => text,
+			(None, Some(message)) => &message.content,
+			(None, None) => "",
+		}
+	}
+}
+
+/// A candidate token and its log probability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopLogprob {
+	pub token: String,
+	pub logprob: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatTokenLogprob {
+	token: String,
+	logprob: f64,
+	#[serde(default)]
+	top_logprobs: Vec<TopLogprob>,
+}
+
+/// Logprobs as either endpoint sends them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawLogprobs {
+	Completion {
+		tokens: Vec<String>,
+		#[serde(default)]
+		token_logprobs: Vec<Option<f64>>,
+		#[serde(default)]
+		top_logprobs: Vec<Option<HashMap<String, f64>>>,
+		#[serde(default)]
+		text_offset: Vec<usize>,
+	},
+	/// As serialized by [`Logprobs`] itself, e.g. in the response cache
+	Normalized {
+		tokens: Vec<String>,
+		token_logprobs: Vec<Option<f64>>,
+		top_logprobs: Vec<Vec<TopLogprob>>,
+		text_offset: Vec<usize>,
+	},
+	Chat {
+		content: Option<Vec<ChatTokenLogprob>>,
+	},
+}
+
+/// Per token log probabilities, in the completions endpoint's shape whichever endpoint sent them
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(from = "RawLogprobs")]
+pub struct Logprobs {
+	pub tokens: Vec<String>,
+	/// Missing for echoed prompt tokens the model didn't score
+	pub token_logprobs: Vec<Option<f64>>,
+	/// Most likely alternatives for each token, most likely first
+	pub top_logprobs: Vec<Vec<TopLogprob>>,
+	/// Offset of each token in the text, only sent by the completions endpoint
+	pub text_offset: Vec<usize>,
+}
+
+impl From<RawLogprobs> for Logprobs {
+	fn from(raw: RawLogprobs) -> Self {
+		match raw {
+			RawLogprobs::Completion {
+				tokens,
+				token_logprobs,
+				top_logprobs,
+				text_offset,
+			} => Self {
+				tokens,
+				token_logprobs,
+				top_logprobs: top_logprobs
+					.into_iter()
+					.map(|top| {
+						let mut top: Vec<TopLogprob> = top
+							.unwrap_or_default()
+							.into_iter()
+							.map(|(token, logprob)| TopLogprob { token, logprob })
+							.collect();
+						top.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+						top
+					})
+					.collect(),
+				text_offset,
+			},
+			RawLogprobs::Normalized {
+				tokens,
+				token_logprobs,
+				top_logprobs,
+				text_offset,
+			} => Self {
+				tokens,
+				token_logprobs,
+				top_logprobs,
+				text_offset,
+			},
+			RawLogprobs::Chat { content } => {
+				let mut logprobs = Self::default();
+				for token in content.unwrap_or_default() {
+					logprobs.tokens.push(token.token);
+					logprobs.token_logprobs.push(Some(token.logprob));
+					logprobs.top_logprobs.push(token.top_logprobs);
+				}
+				logprobs
+			}
+		}
+	}
+}
+
+impl Logprobs {
+	/// Appends the logprobs of a later streamed chunk
+	pub fn extend(&mut self, other: Self) {
+		self.tokens.extend(other.tokens);
+		self.token_logprobs.extend(other.token_logprobs);
+		self.top_logprobs.extend(other.top_logprobs);
+		self.text_offset.extend(other.text_offset);
+	}
+
+	/// Offset of each token, counted from the token lengths when the API didn't send them
+	pub fn offsets(&self) -> Vec<usize> {
+		if self.text_offset.len() == self.tokens.len() {
+			return self.text_offset.clone();
+		}
+		self.tokens
+			.iter()
+			.scan(0, |offset, token| {
+				let start = *offset;
+				*offset += token.len();
+				Some(start)
+			})
+			.collect()
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct GptUsage {
+	pub prompt_tokens: u64,
+	pub completion_tokens: u64,
+	pub total_tokens: u64,
+}
+
+impl AddAssign for GptUsage {
+	fn add_assign(&mut self, other: Self) {
+		self.prompt_tokens += other.prompt_tokens;
+		self.completion_tokens += other.completion_tokens;
+		self.total_tokens += other.total_tokens;
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GptResponse {
+	pub id: Option<String>,
+	pub model: Option<String>,
+	pub choices: Option<Vec<GptChoice>>,
+	pub usage: Option<GptUsage>,
+	/// Body as received, the data of every event for streamed responses
+	#[serde(skip)]
+	pub raw: String,
+	/// Whether this came from the response cache rather than the API
+	#[serde(skip)]
+	pub cached: bool,
+}
@@ -0,0 +1,170 @@
+use crate::error::GptError;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tracing::debug;
+
+/// Tokens kept of each file when `--max-file-tokens` isn't given
+pub const DEFAULT_MAX_FILE_TOKENS: u64 = 4000;
+
+/// Files larger than this are refused rather than read and cut down
+const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Bytes looked at when deciding whether a file is binary
+const SNIFF_BYTES: usize = 8000;
+
+/// A text file whose contents go in front of the next prompt
+#[derive(Debug, Clone)]
+pub struct Attachment {
+	pub name: String,
+	pub text: String,
+	/// Tokens the text was counted at
+	pub tokens: u64,
+	/// Tokens in the whole file when the text had to be cut to fit
+	pub truncated_from: Option<u64>,
+}
+
+impl Attachment {
+	/// Reads `path`, cutting it to `max_tokens` as counted by `count`.
+	/// Binary files and ones too large to read are refused.
+	pub fn read(path: &Path, max_tokens: u64, count: impl Fn(&str) -> u64) -> Result<Self, GptError> {
+		let name = path.display().to_string();
+		let error = |e: std::io::Error| GptError::Config(format!("Can't read {}: {}", name, e));
+		let mut file = File::open(path).map_err(error)?;
+		let size = file.metadata().map_err(error)?.len();
+		if size > MAX_FILE_BYTES {
+			return Err(GptError::Config(format!(
+				"{} is {} bytes, files over {} MB can't be attached",
+				name,
+				size,
+				MAX_FILE_BYTES / (1024 * 1024)
+			)));
+		}
+		let mut bytes = Vec::with_capacity(size as usize);
+		file.read_to_end(&mut bytes).map_err(error)?;
+		let binary = || GptError::Config(format!("{} looks like a binary file, only text can be attached", name));
+		if bytes[..bytes.len().min(SNIFF_BYTES)].contains(&0) {
+			return Err(binary());
+		}
+		let text = String::from_utf8(bytes).map_err(|_| binary())?;
+
+		let tokens = count(&text);
+		debug!("Attaching {}, {} tokens", name, tokens);
+		if tokens <= max_tokens {
+			return Ok(Self {
+				name,
+				text,
+				tokens,
+				truncated_from: None,
+			});
+		}
+		let (text, kept) = truncate(&text, max_tokens, &count);
+		Ok(Self {
+			name,
+			text: text.to_string(),
+			tokens: kept,
+			truncated_from: Some(tokens),
+		})
+	}
+
+	/// The file delimited by its name, with a notice when it was cut short
+	fn block(&self) -> String {
+		let mut block = format!("--- BEGIN FILE: {} ---\n{}", self.name, self.text);
+		if !block.ends_with('\n') {
+			block.push('\n');
+		}
+		if let Some(total) = self.truncated_from {
+			block += &format!(
+				"[truncated: showing the first {} of {} tokens]\n",
+				self.tokens, total
+			);
+		}
+		block + &format!("--- END FILE: {} ---\n", self.name)
+	}
+}
+
+/// The longest start of `text` that fits in `max_tokens`, found by bisecting over char boundaries
+fn truncate(text: &str, max_tokens: u64, count: impl Fn(&str) -> u64) -> (&str, u64) {
+	// Byte offset just past each char, so the first `n` chars end at `ends[n - 1]`
+	let ends: Vec<usize> = text.char_indices().map(|(i, c)| i + c.len_utf8()).collect();
+	let (mut low, mut high) = (0, ends.len());
+	let mut kept = 0;
+	while low < high {
+		let mid = (low + high).div_ceil(2);
+		let tokens = count(&text[..ends[mid - 1]]);
+		if tokens <= max_tokens {
+			low = mid;
+			kept = tokens;
+		} else {
+			high = mid - 1;
+		}
+	}
+	let end = match low {
+		0 => 0,
+		low => ends[low - 1],
+	};
+	// Prefer ending on a whole line when one fits
+	match text[..end].rfind('\n') {
+		Some(newline) if newline > 0 => {
+			let text = &text[..=newline];
+			(text, count(text))
+		}
+		_ => (&text[..end], kept),
+	}
+}
+
+/// `prompt` with each attachment's block in front of it
+pub fn with_prompt(attachments: &[Attachment], prompt: &str) -> String {
+	if attachments.is_empty() {
+		return prompt.to_string();
+	}
+	let mut out: String = attachments.iter().map(|attachment| attachment.block() + "\n").collect();
+	out += prompt;
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn chars(text: &str) -> u64 {
+		text.chars().count() as u64
+	}
+
+	#[test]
+	fn truncate_ends_on_a_whole_line_when_one_fits() {
+		assert_eq!(truncate("abc\ndef\nghi", 9, chars), ("abc\ndef\n", 8));
+		assert_eq!(truncate("abc\ndef\nghi", 8, chars), ("abc\ndef\n", 8));
+	}
+
+	#[test]
+	fn truncate_cuts_mid_line_without_an_earlier_newline() {
+		assert_eq!(truncate("abcdef", 4, chars), ("abcd", 4));
+		assert_eq!(truncate("\nabc", 3, chars), ("\nab", 3));
+		assert_eq!(truncate("abc", 0, chars), ("", 0));
+	}
+
+	#[test]
+	fn truncate_keeps_chars_whole() {
+		assert_eq!(truncate("日本語", 2, chars), ("日本", 2));
+		// Counting bytes, a second `é` would go one over
+		assert_eq!(truncate("éé", 3, |text| text.len() as u64), ("é", 2));
+	}
+
+	#[test]
+	fn blocks_name_the_file_and_note_truncation() {
+		let attachment = Attachment {
+			name: String::from("log.txt"),
+			text: String::from("first\nsecond"),
+			tokens: 2,
+			truncated_from: Some(10),
+		};
+		assert_eq!(
+			with_prompt(&[attachment], "What failed?"),
+			"--- BEGIN FILE: log.txt ---\nfirst\nsecond\n\
+			[truncated: showing the first 2 of 10 tokens]\n\
+			--- END FILE: log.txt ---\n\nWhat failed?"
+		);
+		assert_eq!(with_prompt(&[], "plain"), "plain");
+	}
+}
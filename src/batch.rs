@@ -0,0 +1,474 @@
+use crate::api::{ChatMessage, ChatRequest, GptRequest, GptResponse, GptUsage, Role};
+use crate::error::{ErrorCategory, GptError};
+use crate::pricing::{self, PriceTable};
+use crate::provider::{Backend, Provider};
+use clap::Args;
+use colored::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::debug;
+
+/// Run every prompt in a JSONL file
+#[derive(Debug, Args)]
+pub struct Batch {
+	/// JSONL file of `{"id": ..., "prompt": ...}` objects, other keys override request settings
+	input: PathBuf,
+	/// Where results are written, defaults to the input with a `.out.jsonl` extension
+	#[clap(long)]
+	out: Option<PathBuf>,
+	/// Most requests in flight at once
+	#[clap(short = 'j', long, default_value_t = 4)]
+	concurrency: usize,
+	/// Most requests started per minute
+	#[clap(long)]
+	rpm: Option<u32>,
+	/// Ignore the checkpoint of an earlier run and start over
+	#[clap(long)]
+	fresh: bool,
+}
+
+/// Settings every batch item starts from, before its own overrides
+#[derive(Debug)]
+pub struct BatchDefaults {
+	/// Without a model unless one was given, the default depends on each item's endpoint
+	pub request: GptRequest,
+	pub chat: bool,
+	pub system: Option<String>,
+	/// Most dollars any one item may cost
+	pub max_cost: Option<f64>,
+}
+
+/// One line of the input file
+#[derive(Debug, Deserialize)]
+struct BatchItem {
+	/// Defaults to the line number
+	id: Option<Value>,
+	prompt: String,
+	system: Option<String>,
+	chat: Option<bool>,
+	/// Any other key overrides the request setting of the same name
+	#[serde(flatten)]
+	overrides: Map<String, Value>,
+}
+
+impl BatchItem {
+	/// The request for this item, `defaults` with the item's overrides applied
+	fn request(&self, defaults: &BatchDefaults) -> Result<(GptRequest, bool), GptError> {
+		let mut settings = serde_json::to_value(&defaults.request)?;
+		if let Value::Object(settings) = &mut settings {
+			settings.extend(self.overrides.clone());
+		}
+		let mut request: GptRequest = serde_json::from_value(settings)
+			.map_err(|e| GptError::Config(format!("Invalid setting: {}", e)))?;
+		let chat = self.chat.unwrap_or(defaults.chat);
+		request.prompt = self.prompt.clone();
+		request.stream = false;
+		request.validate(chat)?;
+		Ok((request, chat))
+	}
+}
+
+/// Ids are kept as given when they are strings, other json values are used as written
+fn item_id(id: Option<&Value>, number: usize) -> String {
+	match id {
+		Some(Value::String(id)) => id.clone(),
+		Some(id) => id.to_string(),
+		None => number.to_string(),
+	}
+}
+
+#[derive(Debug, Serialize)]
+struct BatchError {
+	category: ErrorCategory,
+	message: String,
+}
+
+/// One line of the output file
+#[derive(Debug, Serialize)]
+struct BatchResult {
+	id: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	model: Option<String>,
+	/// Text of each choice
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	choices: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	usage: Option<GptUsage>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	cost: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<BatchError>,
+	/// Whether the response came from the cache
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	cached: bool,
+}
+
+impl BatchResult {
+	fn new(id: String, result: Result<GptResponse, GptError>, prices: &PriceTable) -> Self {
+		match result {
+			Ok(response) => Self {
+				id,
+				cost: if response.cached {
+					Some(0.0)
+				} else {
+					response
+						.usage
+						.zip(response.model.as_deref())
+						.and_then(|(usage, model)| prices.cost(model, &usage))
+				},
+				cached: response.cached,
+				choices: response
+					.choices
+					.iter()
+					.flatten()
+					.map(|choice| choice.content().to_string())
+					.collect(),
+				model: response.model,
+				usage: response.usage,
+				error: None,
+			},
+			Err(e) => Self {
+				id,
+				model: None,
+				choices: Vec::new(),
+				usage: None,
+				cost: None,
+				error: Some(BatchError {
+					category: e.category(),
+					message: e.message(),
+				}),
+				cached: false,
+			},
+		}
+	}
+}
+
+/// Sends one item, the system prompt goes before the prompt for the completions endpoint
+async fn send(
+	provider: &Backend,
+	request: GptRequest,
+	chat: bool,
+	system: Option<String>,
+) -> Result<GptResponse, GptError> {
+	if chat {
+		let mut messages = Vec::with_capacity(2);
+		if let Some(system) = system {
+			messages.push(ChatMessage::new(Role::System, system));
+		}
+		messages.push(ChatMessage::new(Role::User, request.prompt.as_str()));
+		provider.chat(&ChatRequest::new(messages, &request)).await
+	} else {
+		let prompt = match system {
+			Some(system) => format!("{}\n\n{}", system, request.prompt),
+			None => request.prompt.clone(),
+		};
+		provider.complete(&GptRequest { prompt, ..request }).await
+	}
+}
+
+/// Ids of items that succeeded in earlier runs, one json string per line
+fn read_checkpoint(path: &Path) -> io::Result<HashSet<String>> {
+	match fs::read_to_string(path) {
+		Ok(text) => Ok(checkpointed_ids(&text)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+		Err(e) => Err(e),
+	}
+}
+
+/// Ids in a checkpoint, lines cut short by an interrupted write are ignored
+fn checkpointed_ids(text: &str) -> HashSet<String> {
+	text.lines()
+		.filter_map(|line| serde_json::from_str(line).ok())
+		.collect()
+}
+
+/// Drops results of items that didn't succeed from an earlier run's output, they are run again
+fn keep_finished(path: &Path, done: &HashSet<String>) -> io::Result<()> {
+	let text = match fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e),
+	};
+	fs::write(path, finished_results(&text, done))
+}
+
+/// The first result line of each finished item
+fn finished_results(text: &str, done: &HashSet<String>) -> String {
+	let mut seen = HashSet::new();
+	text.lines()
+		.filter(|line| {
+			let id = serde_json::from_str::<Value>(line)
+				.ok()
+				.and_then(|result| Some(result.get("id")?.as_str()?.to_string()));
+			id.is_some_and(|id| done.contains(&id) && seen.insert(id))
+		})
+		.map(|line| format!("{}\n", line))
+		.collect()
+}
+
+/// Numbered input lines still to run, and how many were skipped as done.
+/// Lines that don't parse are kept so their error gets reported.
+fn pending_lines<'a>(input: &'a str, done: &HashSet<String>) -> (Vec<(usize, &'a str)>, usize) {
+	let mut lines = Vec::new();
+	let mut skipped = 0;
+	for (i, line) in input.lines().enumerate() {
+		if line.trim().is_empty() {
+			continue;
+		}
+		let id = serde_json::from_str::<BatchItem>(line)
+			.ok()
+			.map(|item| item_id(item.id.as_ref(), i + 1));
+		if id.is_some_and(|id| done.contains(&id)) {
+			skipped += 1;
+		} else {
+			lines.push((i + 1, line));
+		}
+	}
+	(lines, skipped)
+}
+
+fn append(path: &Path) -> io::Result<BufWriter<File>> {
+	let file = OpenOptions::new().create(true).append(true).open(path)?;
+	Ok(BufWriter::new(file))
+}
+
+fn write_line(out: &mut BufWriter<File>, value: &impl serde::Serialize) -> io::Result<()> {
+	serde_json::to_writer(&mut *out, value)?;
+	out.write_all(b"\n")?;
+	out.flush()
+}
+
+/// Writes results as they finish, checkpointing the ids that succeed and reporting progress on stderr
+struct ResultWriter {
+	out: BufWriter<File>,
+	checkpoint: BufWriter<File>,
+	/// Items to run this time
+	total: usize,
+	succeeded: usize,
+	failed: usize,
+	/// Items that succeeded in an earlier run
+	skipped: usize,
+}
+
+impl ResultWriter {
+	fn write(&mut self, result: &BatchResult) -> Result<(), GptError> {
+		let write = |writer: &mut Self| -> io::Result<()> {
+			write_line(&mut writer.out, result)?;
+			if result.error.is_none() {
+				write_line(&mut writer.checkpoint, &result.id)?;
+			}
+			Ok(())
+		};
+		write(self).map_err(|e| GptError::Config(format!("Can't write results: {}", e)))?;
+
+		let status = match &result.error {
+			Some(error) => {
+				self.failed += 1;
+				format!("failed, {}", error.message).red()
+			}
+			None => {
+				self.succeeded += 1;
+				"ok".green()
+			}
+		};
+		eprintln!(
+			"[{}/{}] {} {}",
+			self.succeeded + self.failed,
+			self.total,
+			result.id.yellow(),
+			status
+		);
+		Ok(())
+	}
+}
+
+impl Batch {
+	fn out_path(&self) -> PathBuf {
+		self.out
+			.clone()
+			.unwrap_or_else(|| self.input.with_extension("out.jsonl"))
+	}
+
+	/// Kept next to the output, so a run writing elsewhere starts its own
+	fn checkpoint_path(&self) -> PathBuf {
+		let mut path = self.out_path().into_os_string();
+		path.push(".checkpoint");
+		path.into()
+	}
+
+	/// Ids finished by earlier runs, after trimming the output down to their results
+	fn resume(&self, out_path: &Path, checkpoint_path: &Path) -> io::Result<HashSet<String>> {
+		if self.fresh {
+			for path in [out_path, checkpoint_path] {
+				match fs::remove_file(path) {
+					Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+					_ => {}
+				}
+			}
+			return Ok(HashSet::new());
+		}
+		let done = read_checkpoint(checkpoint_path)?;
+		keep_finished(out_path, &done)?;
+		Ok(done)
+	}
+
+	pub async fn run(
+		&self,
+		provider: Arc<Backend>,
+		defaults: BatchDefaults,
+		prices: Arc<PriceTable>,
+	) -> Result<(), GptError> {
+		let input = fs::read_to_string(&self.input).map_err(|e| {
+			GptError::Config(format!("Can't read {}: {}", self.input.display(), e))
+		})?;
+		let out_path = self.out_path();
+		let checkpoint_path = self.checkpoint_path();
+		let open = || -> io::Result<_> {
+			let done = self.resume(&out_path, &checkpoint_path)?;
+			Ok((done, append(&out_path)?, append(&checkpoint_path)?))
+		};
+		let (done, out, checkpoint) = open().map_err(|e| {
+			GptError::Config(format!("Can't open {}: {}", out_path.display(), e))
+		})?;
+		if !done.is_empty() {
+			debug!("Skipping {} items finished by an earlier run", done.len());
+		}
+
+		let (lines, skipped) = pending_lines(&input, &done);
+		let mut writer = ResultWriter {
+			out,
+			checkpoint,
+			total: lines.len(),
+			succeeded: 0,
+			failed: 0,
+			skipped,
+		};
+
+		debug!("Running {} prompts, {} at a time", lines.len(), self.concurrency);
+		let semaphore = Arc::new(Semaphore::new(self.concurrency.max(1)));
+		let mut pace = self.rpm.filter(|&rpm| rpm > 0).map(|rpm| {
+			let mut pace = time::interval(Duration::from_secs_f64(60.0 / rpm as f64));
+			pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+			pace
+		});
+		let (results, mut finished) = mpsc::unbounded_channel();
+
+		for (number, line) in lines {
+			let item = match serde_json::from_str::<BatchItem>(line) {
+				Ok(item) => item,
+				Err(e) => {
+					let e = GptError::Config(format!("Invalid batch line {}: {}", number, e));
+					writer.write(&BatchResult::new(number.to_string(), Err(e), &prices))?;
+					continue;
+				}
+			};
+			let id = item_id(item.id.as_ref(), number);
+			let (mut request, chat) = match item.request(&defaults) {
+				Ok(request) => request,
+				Err(e) => {
+					writer.write(&BatchResult::new(id, Err(e), &prices))?;
+					continue;
+				}
+			};
+			request
+				.model
+				.get_or_insert_with(|| provider.default_model(chat).to_string());
+			let system = item.system.or_else(|| defaults.system.clone());
+			if let Some(limit) = defaults.max_cost {
+				// No tokenizer here, estimates are close enough to hold items to the limit
+				let prompt_tokens = pricing::estimate_tokens(system.as_deref().unwrap_or_default())
+					+ pricing::estimate_tokens(&request.prompt);
+				let model = request.model.as_deref().unwrap_or_default();
+				let checked = prices.check_budget(model, prompt_tokens, request.max_completion_tokens(), limit);
+				if let Err(e) = checked {
+					writer.write(&BatchResult::new(id, Err(e), &prices))?;
+					continue;
+				}
+			}
+
+			let permit = semaphore.clone().acquire_owned().await.expect("semaphore closed");
+			if let Some(pace) = &mut pace {
+				pace.tick().await;
+			}
+			let (provider, prices, results) = (provider.clone(), prices.clone(), results.clone());
+			tokio::spawn(async move {
+				let result = send(&provider, request, chat, system).await;
+				results.send(BatchResult::new(id, result, &prices)).ok();
+				drop(permit);
+			});
+
+			while let Ok(result) = finished.try_recv() {
+				writer.write(&result)?;
+			}
+		}
+		drop(results);
+		while let Some(result) = finished.recv().await {
+			writer.write(&result)?;
+		}
+
+		eprintln!(
+			"{} {} succeeded, {} failed, {} skipped, results in {}",
+			"Batch finished:".green(),
+			writer.succeeded.to_string().green(),
+			writer.failed.to_string().red(),
+			writer.skipped.to_string().yellow(),
+			out_path.display()
+		);
+		if writer.failed > 0 {
+			eprintln!("{}", "Run the same command again to retry the failed items".yellow());
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ids(ids: &[&str]) -> HashSet<String> {
+		ids.iter().map(|id| id.to_string()).collect()
+	}
+
+	#[test]
+	fn checkpoints_ignore_partly_written_lines() {
+		assert_eq!(checkpointed_ids("\"a\"\n\"b\"\n\"c"), ids(&["a", "b"]));
+		assert_eq!(checkpointed_ids(""), ids(&[]));
+	}
+
+	#[test]
+	fn finished_results_drop_failures_and_duplicates() {
+		let output = concat!(
+			"{\"id\":\"a\",\"choices\":[\"one\"]}\n",
+			"{\"id\":\"b\",\"error\":{}}\n",
+			"{\"id\":\"a\",\"choices\":[\"again\"]}\n",
+			"{\"id\":\"c\",\"choi",
+		);
+		assert_eq!(
+			finished_results(output, &ids(&["a", "c"])),
+			"{\"id\":\"a\",\"choices\":[\"one\"]}\n"
+		);
+	}
+
+	#[test]
+	fn pending_lines_skip_done_items_by_id_or_line_number() {
+		let input = concat!(
+			"{\"id\":\"x\",\"prompt\":\"a\"}\n",
+			"\n",
+			"{\"prompt\":\"b\"}\n",
+			"{\"id\":7,\"prompt\":\"c\"}\n",
+			"{\"prompt\":\"d\"}\n",
+			"not json\n",
+		);
+		let (lines, skipped) = pending_lines(input, &ids(&["x", "3", "7"]));
+		assert_eq!(skipped, 3);
+		assert_eq!(lines, [(5, "{\"prompt\":\"d\"}"), (6, "not json")]);
+	}
+}
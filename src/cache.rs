@@ -0,0 +1,270 @@
+use crate::api::GptResponse;
+use crate::error::GptError;
+use crate::sessions::now;
+use clap::{Args, Subcommand};
+use colored::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const EXTENSION: &str = "json";
+
+/// `$XDG_CACHE_HOME/gpt-rust/responses` or the platform equivalent
+pub fn cache_dir() -> Option<PathBuf> {
+	dirs::cache_dir().map(|dir| dir.join("gpt-rust").join("responses"))
+}
+
+// When responses to deterministic requests are reused
+#[derive(Debug, Clone, Args)]
+pub struct CacheOptions {
+	/// Neither read nor write the response cache
+	#[clap(long)]
+	no_cache: bool,
+	/// Send requests even if a cached response exists, then cache the new one
+	#[clap(long, conflicts_with = "no-cache")]
+	refresh: bool,
+	/// Seconds a cached response stays valid, 0 keeps them until evicted
+	#[clap(long, env = "GPT_CACHE_TTL", default_value_t = 7 * 24 * 60 * 60)]
+	cache_ttl: u64,
+	/// Megabytes the cache may use before the least recently used responses are evicted
+	#[clap(long, env = "GPT_CACHE_MAX_SIZE", default_value_t = 100)]
+	cache_max_size: u64,
+}
+
+impl CacheOptions {
+	/// The cache these options describe, none when turned off
+	pub fn cache(&self) -> Option<Cache> {
+		if self.no_cache {
+			return None;
+		}
+		Some(Cache {
+			dir: cache_dir()?,
+			read: !self.refresh,
+			ttl: (self.cache_ttl > 0).then(|| Duration::from_secs(self.cache_ttl)),
+			max_size: self.cache_max_size * 1024 * 1024,
+		})
+	}
+}
+
+/// A cached response and when it was stored
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+	/// Unix time the response arrived
+	created: u64,
+	uri: String,
+	response: GptResponse,
+	/// Body as received, for `--output raw`
+	raw: String,
+}
+
+/// On-disk store of responses to deterministic requests, keyed by a hash of the endpoint and body
+#[derive(Debug, Clone)]
+pub struct Cache {
+	dir: PathBuf,
+	/// Whether cached responses are used, otherwise they are only written
+	read: bool,
+	ttl: Option<Duration>,
+	/// Bytes
+	max_size: u64,
+}
+
+impl Cache {
+	/// Key for a request, none unless its temperature is 0 so the same answer is expected.
+	/// Streamed and plain requests get different keys, their raw bodies and usage differ.
+	pub fn key(uri: &str, body: &[u8]) -> Option<String> {
+		let body: Value = serde_json::from_slice(body).ok()?;
+		if body.get("temperature").and_then(Value::as_f64) != Some(0.0) {
+			return None;
+		}
+
+		// Object keys are sorted, so equal requests serialize the same way
+		let hash = Sha256::new()
+			.chain_update(uri)
+			.chain_update(b"\n")
+			.chain_update(body.to_string())
+			.finalize();
+		let mut key = String::with_capacity(64);
+		for byte in hash {
+			write!(key, "{:02x}", byte).ok();
+		}
+		Some(key)
+	}
+
+	fn path(&self, key: &str) -> PathBuf {
+		self.dir.join(key).with_extension(EXTENSION)
+	}
+
+	fn is_expired(&self, created: u64) -> bool {
+		let age = now().saturating_sub(created);
+		self.ttl.is_some_and(|ttl| age > ttl.as_secs())
+	}
+
+	/// The stored response for `key`, if there is one and it hasn't expired
+	pub fn get(&self, key: &str) -> Option<GptResponse> {
+		if !self.read {
+			return None;
+		}
+		let path = self.path(key);
+		let entry: Entry = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+		if self.is_expired(entry.created) {
+			debug!("Cached response {} expired", key);
+			fs::remove_file(&path).ok();
+			return None;
+		}
+		debug!("Using cached response {}", key);
+		// Evicting goes by modification time, so mark the entry as just used
+		if let Ok(file) = File::options().write(true).open(&path) {
+			file.set_modified(SystemTime::now()).ok();
+		}
+		Some(GptResponse {
+			raw: entry.raw,
+			cached: true,
+			..entry.response
+		})
+	}
+
+	/// Stores `response`, failures are only warned about since the request itself succeeded
+	pub fn put(&self, key: &str, uri: &str, response: &GptResponse) {
+		let entry = Entry {
+			created: now(),
+			uri: uri.to_string(),
+			response: response.clone(),
+			raw: response.raw.clone(),
+		};
+		let write = || -> Result<(), Box<dyn std::error::Error>> {
+			fs::create_dir_all(&self.dir)?;
+			fs::write(self.path(key), serde_json::to_vec(&entry)?)?;
+			Ok(())
+		};
+		match write() {
+			Ok(()) => debug!("Cached response {}", key),
+			Err(e) => warn!("Can't cache response: {}", e),
+		}
+		self.evict();
+	}
+
+	/// Removes the least recently used responses until the cache fits in its max size
+	fn evict(&self) {
+		let mut files = cached_files(&self.dir);
+		let mut size: u64 = files.iter().map(|file| file.size).sum();
+		if size <= self.max_size {
+			return;
+		}
+		files.sort_by_key(|file| file.modified);
+		for file in files {
+			if size <= self.max_size {
+				break;
+			}
+			debug!("Evicting {}", file.path.display());
+			if fs::remove_file(&file.path).is_ok() {
+				size -= file.size;
+			}
+		}
+	}
+}
+
+struct CachedFile {
+	path: PathBuf,
+	size: u64,
+	modified: SystemTime,
+}
+
+fn cached_files(dir: &Path) -> Vec<CachedFile> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(_) => return Vec::new(),
+	};
+	entries
+		.flatten()
+		.filter(|entry| entry.path().extension().is_some_and(|ext| ext == EXTENSION))
+		.filter_map(|entry| {
+			let metadata = entry.metadata().ok()?;
+			Some(CachedFile {
+				path: entry.path(),
+				size: metadata.len(),
+				modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+			})
+		})
+		.collect()
+}
+
+/// Inspect or empty the response cache
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+	/// Show how many responses are cached and the space they use
+	Stats,
+	/// Delete cached responses
+	Clear,
+}
+
+impl CacheCommand {
+	pub fn run(&self) -> Result<(), GptError> {
+		let dir = cache_dir()
+			.ok_or_else(|| GptError::Config(String::from("No cache directory to keep responses in")))?;
+		let files = cached_files(&dir);
+		match self {
+			Self::Stats => {
+				let size: u64 = files.iter().map(|file| file.size).sum();
+				println!("{} {}", "Cache:".cyan(), dir.display());
+				println!("{} {}", "Responses:".cyan(), files.len().to_string().yellow());
+				println!(
+					"{} {}",
+					"Size:".cyan(),
+					format!("{:.2} MB", size as f64 / (1024.0 * 1024.0)).yellow()
+				);
+				if let Some(oldest) = files.iter().map(|file| file.modified).min() {
+					println!("{} {}", "Least recently used:".cyan(), httpdate::fmt_http_date(oldest));
+				}
+			}
+			Self::Clear => {
+				let mut removed = 0;
+				for file in &files {
+					match fs::remove_file(&file.path) {
+						Ok(()) => removed += 1,
+						Err(e) => warn!("Can't remove {}: {}", file.path.display(), e),
+					}
+				}
+				println!("{} {} cached responses", "Removed".red(), removed);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const URI: &str = "https://api.openai.com/v1/completions";
+
+	#[test]
+	fn only_deterministic_requests_have_keys() {
+		assert!(Cache::key(URI, br#"{"prompt":"hi","temperature":0}"#).is_some());
+		assert!(Cache::key(URI, br#"{"prompt":"hi","temperature":0.0}"#).is_some());
+		assert!(Cache::key(URI, br#"{"prompt":"hi","temperature":0.7}"#).is_none());
+		assert!(Cache::key(URI, br#"{"prompt":"hi"}"#).is_none());
+		assert!(Cache::key(URI, b"not json").is_none());
+	}
+
+	#[test]
+	fn keys_ignore_field_order_and_formatting() {
+		assert_eq!(
+			Cache::key(URI, br#"{"prompt":"hi","temperature":0,"n":1}"#),
+			Cache::key(URI, br#"{ "n": 1, "temperature": 0, "prompt": "hi" }"#)
+		);
+	}
+
+	#[test]
+	fn keys_differ_by_endpoint_body_and_streaming() {
+		let body = br#"{"prompt":"hi","temperature":0}"#;
+		let key = Cache::key(URI, body);
+		assert_ne!(key, Cache::key("https://api.openai.com/v1/chat/completions", body));
+		assert_ne!(key, Cache::key(URI, br#"{"prompt":"ho","temperature":0}"#));
+		assert_ne!(key, Cache::key(URI, br#"{"prompt":"hi","temperature":0,"stream":true}"#));
+	}
+}
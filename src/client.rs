@@ -0,0 +1,97 @@
+use crate::api::GptResponse;
+use crate::error::{ErrorEnvelope, GptError};
+use hyper::client::HttpConnector;
+use hyper::http::uri::InvalidUri;
+use hyper::{header, Body, Client, Request, Response, Uri};
+use hyper_tls::HttpsConnector;
+use std::time::Duration;
+use tracing::debug;
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn new_client(connect_timeout: Option<Duration>) -> HttpsClient {
+	debug!("Setting up https connector");
+	let mut http = HttpConnector::new();
+	http.enforce_http(false);
+	http.set_connect_timeout(connect_timeout);
+	let https = HttpsConnector::new_with_connector(http);
+
+	debug!("Setting up client");
+	Client::builder().build(https)
+}
+
+/// Joins `path` onto `base_url`, keeping any query string (e.g. an api-version) at the end
+pub fn endpoint(base_url: &str, path: &str) -> String {
+	let (base, query) = match base_url.split_once('?') {
+		Some((base, query)) => (base, Some(query)),
+		None => (base_url, None),
+	};
+	let mut uri = format!("{}/{}", base.trim_end_matches('/'), path);
+	if let Some(query) = query {
+		uri.push('?');
+		uri.push_str(query);
+	}
+	uri
+}
+
+/// Fails unless `base_url` is an absolute http(s) url that endpoints can be joined onto
+pub fn check_base_url(base_url: &str) -> Result<(), GptError> {
+	let invalid = |reason: String| GptError::Config(format!("Invalid base url `{}`: {}", base_url, reason));
+	let uri: Uri = endpoint(base_url, "").parse().map_err(|e: InvalidUri| invalid(e.to_string()))?;
+	match uri.scheme_str() {
+		Some("http" | "https") if uri.authority().is_some() => Ok(()),
+		Some("http" | "https") => Err(invalid(String::from("missing host"))),
+		_ => Err(invalid(String::from("expected an http:// or https:// url"))),
+	}
+}
+
+/// Posts a json `body` to `uri`, returning the raw response
+pub async fn post(
+	client: &HttpsClient,
+	uri: &str,
+	auth: Option<(&str, &str)>,
+	body: Vec<u8>,
+) -> Result<Response<Body>, GptError> {
+	let body = Body::from(body);
+	debug!("Request: {:?}", body);
+
+	debug!("Creating Request");
+	let mut req = Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+	if let Some((name, value)) = auth {
+		req = req.header(name, value);
+	}
+	let req = req
+		.body(body)
+		.map_err(|e| GptError::Config(format!("Can't build request to {}: {}", uri, e)))?;
+
+	debug!("Sending Request");
+	let res = client.request(req).await?;
+	let status = res.status();
+	debug!("Got Response, Status: {}", status);
+	if !status.is_success() {
+		let headers = res.headers().clone();
+		let body = hyper::body::to_bytes(res).await?;
+		return Err(GptError::from_response(status, &headers, &body));
+	}
+	Ok(res)
+}
+
+/// Reads a whole json response body
+pub async fn read_json(res: Response<Body>) -> Result<GptResponse, GptError> {
+	let status = res.status();
+	debug!("Getting Body");
+	let body = hyper::body::to_bytes(res).await?;
+
+	debug!("Deserializing Body");
+	if let Ok(envelope) = serde_json::from_slice::<ErrorEnvelope>(&body) {
+		return Err(GptError::Api {
+			status,
+			error: envelope.error,
+			retry_after: None,
+		});
+	}
+	let mut json: GptResponse = serde_json::from_slice(&body)?;
+	json.raw = String::from_utf8_lossy(&body).into_owned();
+	debug!("Json Received: {:#?}", json);
+	Ok(json)
+}
@@ -0,0 +1,247 @@
+use crate::{sessions, tokenizer};
+use colored::*;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// A command the REPL understands, with its arguments and what it does
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+	pub name: &'static str,
+	pub args: &'static str,
+	pub help: &'static str,
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+	CommandInfo {
+		name: "/temp",
+		args: "[0-2]",
+		help: "Show or set the temperature",
+	},
+	CommandInfo {
+		name: "/max",
+		args: "[tokens]",
+		help: "Show or set the max tokens per response",
+	},
+	CommandInfo {
+		name: "/model",
+		args: "[name]",
+		help: "Show or set the model",
+	},
+	CommandInfo {
+		name: "/system",
+		args: "[prompt]",
+		help: "Show or set the system prompt",
+	},
+	CommandInfo {
+		name: "/clear",
+		args: "",
+		help: "Forget the conversation so far",
+	},
+	CommandInfo {
+		name: "/save",
+		args: "[name]",
+		help: "Save the session by name or to a json file",
+	},
+	CommandInfo {
+		name: "/load",
+		args: "<name>",
+		help: "Continue a session saved by /save",
+	},
+	CommandInfo {
+		name: "/attach",
+		args: "[path]",
+		help: "Send a file with the next prompt, or list the files attached",
+	},
+	CommandInfo {
+		name: "/usage",
+		args: "",
+		help: "Show the tokens and cost of this session",
+	},
+	CommandInfo {
+		name: "/paste",
+		args: "",
+		help: "Take every line as the prompt until /end",
+	},
+	CommandInfo {
+		name: "/help",
+		args: "",
+		help: "List the commands",
+	},
+	CommandInfo {
+		name: "/exit",
+		args: "",
+		help: "Quit",
+	},
+];
+
+/// Values offered when completing `/temp`
+const TEMPERATURES: &[&str] = &["0", "0.3", "0.7", "1", "1.5"];
+
+/// A slash command typed at the prompt
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlashCommand {
+	Temperature(Option<f64>),
+	MaxTokens(Option<usize>),
+	Model(Option<String>),
+	System(Option<String>),
+	Clear,
+	/// Saves under the name given, or where the session was last saved
+	Save(Option<String>),
+	Load(String),
+	/// Attaches the file to the next prompt, or lists what is attached
+	Attach(Option<String>),
+	Usage,
+	Help,
+	Exit,
+}
+
+impl SlashCommand {
+	/// Parses `line` if it is a command, lines starting with `//` are prompts with the first `/` removed
+	pub fn parse(line: &str) -> Option<Result<Self, String>> {
+		let line = line.trim();
+		if !line.starts_with('/') || line.starts_with("//") {
+			return None;
+		}
+		let (name, arg) = match line.split_once(char::is_whitespace) {
+			Some((name, arg)) => (name, Some(arg.trim()).filter(|arg| !arg.is_empty())),
+			None => (line, None),
+		};
+		Some(match name {
+			"/temp" => number(name, arg).map(Self::Temperature),
+			"/max" => number(name, arg).map(Self::MaxTokens),
+			"/model" => Ok(Self::Model(arg.map(String::from))),
+			"/system" => Ok(Self::System(arg.map(String::from))),
+			"/clear" => Ok(Self::Clear),
+			"/save" => Ok(Self::Save(arg.map(String::from))),
+			"/load" => arg
+				.map(|arg| Self::Load(arg.to_string()))
+				.ok_or_else(|| String::from("/load expects a session name or file")),
+			"/attach" => Ok(Self::Attach(arg.map(String::from))),
+			"/usage" => Ok(Self::Usage),
+			"/help" => Ok(Self::Help),
+			"/exit" => Ok(Self::Exit),
+			_ => Err(format!("Unknown command {}, /help lists them", name)),
+		})
+	}
+}
+
+fn number<T: FromStr>(name: &str, arg: Option<&str>) -> Result<Option<T>, String> {
+	arg.map(|arg| {
+		arg.parse()
+			.map_err(|_| format!("{} expects a number, got `{}`", name, arg))
+	})
+	.transpose()
+}
+
+/// Prints every command with its arguments
+pub fn print_help() {
+	println!("{}", "Commands".green());
+	for command in COMMANDS {
+		println!(
+			"  {:<24} {}",
+			format!("{} {}", command.name, command.args).cyan(),
+			command.help
+		);
+	}
+	println!(
+		"{}\n",
+		"End a line with \\ to continue it, start a prompt with // to send a leading /".yellow()
+	);
+}
+
+/// Completes and hints command names and their arguments at the prompt
+pub struct CommandHelper {
+	files: FilenameCompleter,
+}
+
+impl CommandHelper {
+	pub fn new() -> Self {
+		Self {
+			files: FilenameCompleter::new(),
+		}
+	}
+}
+
+fn find(name: &str) -> Option<&'static CommandInfo> {
+	COMMANDS.iter().find(|command| command.name == name)
+}
+
+fn pairs<'a>(candidates: impl Iterator<Item = &'a str>, prefix: &str) -> Vec<Pair> {
+	candidates
+		.filter(|candidate| candidate.starts_with(prefix))
+		.map(|candidate| Pair {
+			display: candidate.to_string(),
+			replacement: candidate.to_string(),
+		})
+		.collect()
+}
+
+impl Completer for CommandHelper {
+	type Candidate = Pair;
+
+	fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+		let typed = &line[..pos];
+		if !typed.starts_with('/') {
+			return Ok((pos, Vec::new()));
+		}
+		let (name, arg) = match typed.split_once(' ') {
+			Some(split) => split,
+			None => {
+				let names = pairs(COMMANDS.iter().map(|c| c.name), typed);
+				return Ok((0, names));
+			}
+		};
+		let start = name.len() + 1;
+		match name {
+			"/model" => Ok((start, pairs(tokenizer::known_models(), arg))),
+			"/temp" => Ok((start, pairs(TEMPERATURES.iter().copied(), arg))),
+			"/attach" => self.files.complete_path(line, pos),
+			"/save" | "/load" if arg.contains('/') => self.files.complete_path(line, pos),
+			"/save" | "/load" => {
+				let names = sessions::session_names();
+				Ok((start, pairs(names.iter().map(String::as_str), arg)))
+			}
+			_ => Ok((pos, Vec::new())),
+		}
+	}
+}
+
+impl Hinter for CommandHelper {
+	type Hint = String;
+
+	/// The rest of a command name being typed, or the arguments it takes
+	fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+		if pos < line.len() || !line.starts_with('/') || line.len() < 2 {
+			return None;
+		}
+		if let Some(name) = line.strip_suffix(' ') {
+			return find(name)
+				.filter(|command| !command.args.is_empty())
+				.map(|command| command.args.to_string());
+		}
+		let command = COMMANDS
+			.iter()
+			.find(|command| command.name.starts_with(line))?;
+		let rest = &command.name[line.len()..];
+		match command.args {
+			"" if rest.is_empty() => None,
+			"" => Some(rest.to_string()),
+			args => Some(format!("{} {}", rest, args)),
+		}
+	}
+}
+
+impl Highlighter for CommandHelper {
+	fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+		Cow::Owned(hint.dimmed().to_string())
+	}
+}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
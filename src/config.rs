@@ -0,0 +1,97 @@
+use crate::api::GptRequest;
+use crate::error::GptError;
+use crate::pricing::Price;
+use crate::provider::ProviderKind;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Env var holding the API key when a profile doesn't name one
+pub const DEFAULT_API_KEY_ENV: &str = "OPENAI_TOKEN";
+
+/// Named group of settings selected with `--profile`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+	pub provider: Option<ProviderKind>,
+	pub model: Option<String>,
+	pub base_url: Option<String>,
+	pub chat: Option<bool>,
+	pub temperature: Option<f64>,
+	pub max_tokens: Option<usize>,
+	pub stop: Option<Vec<String>>,
+	pub system: Option<String>,
+	/// Env var to read the API key from instead of `OPENAI_TOKEN`
+	pub api_key_env: Option<String>,
+	/// Header to send the bare API key in, such as Azure's `api-key`, instead of a bearer token
+	pub auth_header: Option<String>,
+}
+
+impl Profile {
+	/// Fills request settings not already given on the command line or in the env
+	pub fn apply(&self, request: &mut GptRequest) {
+		if request.model.is_none() {
+			request.model = self.model.clone();
+		}
+		request.temperature = request.temperature.or(self.temperature);
+		request.max_tokens = request.max_tokens.or(self.max_tokens);
+		if request.stop.is_empty() {
+			request.stop = self.stop.clone().unwrap_or_default();
+		}
+	}
+
+	pub fn api_key_env(&self) -> &str {
+		self.api_key_env.as_deref().unwrap_or(DEFAULT_API_KEY_ENV)
+	}
+}
+
+/// Contents of `config.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Profile used when `--profile` isn't given
+	pub default_profile: Option<String>,
+	pub profiles: HashMap<String, Profile>,
+	/// Per-model prices overriding the shipped ones
+	pub prices: HashMap<String, Price>,
+	/// Prompts kept in the REPL history file
+	pub history_size: Option<usize>,
+}
+
+impl Config {
+	/// `$XDG_CONFIG_HOME/gpt-rust/config.toml` or the platform equivalent
+	pub fn default_path() -> Option<PathBuf> {
+		dirs::config_dir().map(|dir| dir.join("gpt-rust").join("config.toml"))
+	}
+
+	/// Reads the config at `path`, or at the default path if it exists
+	pub fn load(path: Option<&Path>) -> Result<Self, GptError> {
+		let path = match path {
+			Some(path) => path.to_path_buf(),
+			None => match Self::default_path() {
+				Some(path) if path.exists() => path,
+				_ => {
+					debug!("No config file found, using defaults");
+					return Ok(Self::default());
+				}
+			},
+		};
+		debug!("Loading config from {}", path.display());
+		let text = fs::read_to_string(&path)
+			.map_err(|e| GptError::Config(format!("Can't read {}: {}", path.display(), e)))?;
+		toml::from_str(&text)
+			.map_err(|e| GptError::Config(format!("Invalid config {}: {}", path.display(), e)))
+	}
+
+	/// The named profile, or the default one, or empty settings when there is none
+	pub fn profile(&self, name: Option<&str>) -> Result<Profile, GptError> {
+		match name.or(self.default_profile.as_deref()) {
+			Some(name) => self.profiles.get(name).cloned().ok_or_else(|| {
+				GptError::Config(format!("No profile named {} in the config", name))
+			}),
+			None => Ok(Profile::default()),
+		}
+	}
+}
@@ -0,0 +1,193 @@
+use crate::api::{ChatMessage, GptUsage, Role};
+use serde_derive::{Deserialize, Serialize};
+
+const USER_LABEL: &str = "Human:";
+const ASSISTANT_LABEL: &str = "AI:";
+
+/// A single prompt and the response chosen for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Turn {
+	pub prompt: String,
+	pub response: String,
+	/// Completion id of the response
+	#[serde(default)]
+	pub id: Option<String>,
+	/// Model that answered
+	#[serde(default)]
+	pub model: Option<String>,
+	#[serde(default)]
+	pub usage: Option<GptUsage>,
+	/// Unix time the response arrived
+	#[serde(default)]
+	pub timestamp: u64,
+}
+
+impl Turn {
+	fn size(&self) -> usize {
+		format_turn(&self.prompt, Some(&self.response)).len()
+	}
+}
+
+/// Every turn so far, of which the newest that fit are sent as context with each request
+#[derive(Debug)]
+pub struct Conversation {
+	turns: Vec<Turn>,
+	/// Max characters of assembled context, older turns are left out to fit
+	budget: usize,
+}
+
+impl Conversation {
+	pub fn new(budget: usize) -> Self {
+		Self {
+			turns: Vec::new(),
+			budget,
+		}
+	}
+
+	pub fn push(&mut self, mut turn: Turn) {
+		turn.response = turn.response.trim().to_string();
+		self.turns.push(turn);
+	}
+
+	pub fn len(&self) -> usize {
+		self.turns.len()
+	}
+
+	pub fn clear(&mut self) {
+		self.turns.clear();
+	}
+
+	pub fn turns(&self) -> &[Turn] {
+		&self.turns
+	}
+
+	/// Replaces the conversation with `turns`, oldest first
+	pub fn restore(&mut self, turns: Vec<Turn>) {
+		self.turns = turns;
+	}
+
+	/// The newest turns that fit in the budget alongside `reserved` characters
+	fn context(&self, reserved: usize) -> &[Turn] {
+		let mut size = reserved;
+		let kept = self
+			.turns
+			.iter()
+			.rev()
+			.take_while(|turn| {
+				size += turn.size();
+				size <= self.budget
+			})
+			.count();
+		&self.turns[self.turns.len() - kept..]
+	}
+
+	/// Builds the full completions prompt for `prompt`
+	pub fn build_prompt(&self, system: Option<&str>, prompt: &str) -> String {
+		let preamble = preamble(system);
+		let current = format_turn(prompt, None);
+
+		let mut out = String::new();
+		for turn in self.context(preamble.len() + current.len()) {
+			out += &format_turn(&turn.prompt, Some(&turn.response));
+		}
+		preamble + &out + &current
+	}
+
+	/// Builds the completions prompt for a one-shot run, which sends `prompt` exactly as given
+	/// unless a resumed conversation has to frame it
+	pub fn one_shot_prompt(&self, system: Option<&str>, prompt: &str) -> String {
+		if !self.turns.is_empty() {
+			return self.build_prompt(system, prompt);
+		}
+		preamble(system) + prompt
+	}
+
+	/// Builds the chat messages for `prompt`
+	pub fn messages(&self, system: Option<&str>, prompt: &str) -> Vec<ChatMessage> {
+		let reserved = system.map_or(0, str::len) + format_turn(prompt, None).len();
+		let context = self.context(reserved);
+
+		let mut messages = Vec::with_capacity(context.len() * 2 + 2);
+		if let Some(system) = system {
+			messages.push(ChatMessage::new(Role::System, system));
+		}
+		for turn in context {
+			messages.push(ChatMessage::new(Role::User, turn.prompt.as_str()));
+			messages.push(ChatMessage::new(Role::Assistant, turn.response.as_str()));
+		}
+		messages.push(ChatMessage::new(Role::User, prompt));
+		messages
+	}
+}
+
+fn preamble(system: Option<&str>) -> String {
+	system.map(|s| format!("{}\n\n", s)).unwrap_or_default()
+}
+
+fn format_turn(prompt: &str, response: Option<&str>) -> String {
+	match response {
+		Some(response) => format!(
+			"{} {}\n{} {}\n",
+			USER_LABEL, prompt, ASSISTANT_LABEL, response
+		),
+		None => format!("{} {}\n{}", USER_LABEL, prompt, ASSISTANT_LABEL),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn turn(prompt: &str, response: &str) -> Turn {
+		Turn {
+			prompt: prompt.to_string(),
+			response: response.to_string(),
+			id: None,
+			model: None,
+			usage: None,
+			timestamp: 0,
+		}
+	}
+
+	/// Each turn formats to `Human: pN\nAI: rN\n`, 17 characters
+	fn conversation(budget: usize, turns: usize) -> Conversation {
+		let mut conversation = Conversation::new(budget);
+		for i in 1..=turns {
+			conversation.push(turn(&format!("p{}", i), &format!("r{}", i)));
+		}
+		conversation
+	}
+
+	fn prompts(turns: &[Turn]) -> Vec<&str> {
+		turns.iter().map(|turn| turn.prompt.as_str()).collect()
+	}
+
+	#[test]
+	fn context_keeps_the_newest_turns_that_fit() {
+		let conversation = conversation(40, 3);
+		assert_eq!(prompts(conversation.context(0)), ["p2", "p3"]);
+		assert_eq!(prompts(conversation.context(6)), ["p2", "p3"]);
+		assert_eq!(prompts(conversation.context(7)), ["p3"]);
+		assert_eq!(prompts(conversation.context(24)), Vec::<&str>::new());
+	}
+
+	#[test]
+	fn context_stops_at_the_first_turn_that_doesnt_fit() {
+		let mut conversation = conversation(40, 1);
+		conversation.push(turn("a long prompt that takes most of the budget", "r"));
+		conversation.push(turn("p3", "r3"));
+		assert_eq!(prompts(conversation.context(0)), ["p3"]);
+	}
+
+	#[test]
+	fn build_prompt_frames_context_after_the_system_prompt() {
+		let conversation = conversation(1000, 1);
+		assert_eq!(
+			conversation.build_prompt(Some("Be brief"), "next"),
+			"Be brief\n\nHuman: p1\nAI: r1\nHuman: next\nAI:"
+		);
+		assert_eq!(Conversation::new(1000).build_prompt(None, "hi"), "Human: hi\nAI:");
+		assert_eq!(Conversation::new(1000).one_shot_prompt(Some("sys"), "hi"), "sys\n\nhi");
+		assert_eq!(conversation.one_shot_prompt(None, "hi"), "Human: p1\nAI: r1\nHuman: hi\nAI:");
+	}
+}
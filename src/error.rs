@@ -0,0 +1,249 @@
+use colored::*;
+use hyper::{header, HeaderMap, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Error object returned by the API, e.g. `{"error": {"message": ..., "type": ..., "code": ...}}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+	pub message: String,
+	#[serde(rename = "type")]
+	pub kind: Option<String>,
+	/// Usually a string, but some servers send numbers
+	pub code: Option<serde_json::Value>,
+}
+
+impl ApiError {
+	/// Category going by the error's type, for errors that came without a telling status
+	fn category(&self) -> ErrorCategory {
+		match self.kind.as_deref().unwrap_or_default() {
+			"authentication_error" | "permission_error" | "invalid_api_key" => ErrorCategory::Auth,
+			"rate_limit_error" | "rate_limit_exceeded" => ErrorCategory::RateLimit,
+			"server_error" | "api_error" | "overloaded_error" | "service_unavailable" => {
+				ErrorCategory::Server
+			}
+			_ => ErrorCategory::InvalidRequest,
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorEnvelope {
+	pub error: ApiError,
+}
+
+/// Broad class of an error, each exits with its own code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+	Config,
+	Auth,
+	RateLimit,
+	InvalidRequest,
+	Server,
+	Transport,
+	Timeout,
+	Decode,
+	Budget,
+	Cancelled,
+}
+
+impl ErrorCategory {
+	pub fn exit_code(self) -> i32 {
+		match self {
+			Self::Config => 2,
+			Self::Auth => 3,
+			Self::RateLimit => 4,
+			Self::InvalidRequest => 5,
+			Self::Server => 6,
+			Self::Transport => 7,
+			Self::Timeout => 8,
+			Self::Decode => 9,
+			Self::Budget => 10,
+			// Matches the shell convention for processes ended by SIGINT
+			Self::Cancelled => 130,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum GptError {
+	/// The API answered with an error, `status` is 200 for errors sent mid-stream
+	Api {
+		status: StatusCode,
+		error: ApiError,
+		/// How long the server asked us to wait before trying again
+		retry_after: Option<Duration>,
+	},
+	Transport(hyper::Error),
+	/// No response arrived within the configured timeout
+	Timeout(Duration),
+	/// The user pressed Ctrl-C while the request was in flight
+	Cancelled,
+	/// The request could cost more than `--max-cost` allows
+	OverBudget { estimate: f64, limit: f64 },
+	/// `--max-cost` is set but the model has no known price to check it against
+	UnknownPrice(String),
+	Json(serde_json::Error),
+	Config(String),
+}
+
+impl GptError {
+	/// Builds an error from a non-2xx response body, falling back to the raw text
+	pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+		let error = match serde_json::from_slice::<ErrorEnvelope>(body) {
+			Ok(envelope) => envelope.error,
+			Err(_) => ApiError {
+				message: String::from_utf8_lossy(body).trim().to_string(),
+				kind: None,
+				code: None,
+			},
+		};
+		Self::Api {
+			status,
+			error,
+			retry_after: retry_after(headers),
+		}
+	}
+
+	pub fn category(&self) -> ErrorCategory {
+		match self {
+			// Errors sent in a successful response only say what went wrong in their type
+			Self::Api { status, error, .. } if *status == StatusCode::OK => error.category(),
+			Self::Api { status, .. } => match *status {
+				StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorCategory::Auth,
+				StatusCode::TOO_MANY_REQUESTS => ErrorCategory::RateLimit,
+				s if s.is_server_error() => ErrorCategory::Server,
+				_ => ErrorCategory::InvalidRequest,
+			},
+			Self::Transport(_) => ErrorCategory::Transport,
+			Self::Timeout(_) => ErrorCategory::Timeout,
+			Self::Cancelled => ErrorCategory::Cancelled,
+			Self::OverBudget { .. } | Self::UnknownPrice(_) => ErrorCategory::Budget,
+			Self::Json(_) => ErrorCategory::Decode,
+			Self::Config(_) => ErrorCategory::Config,
+		}
+	}
+
+	/// Whether sending the same request again may succeed
+	pub fn is_retryable(&self) -> bool {
+		matches!(
+			self.category(),
+			ErrorCategory::RateLimit
+				| ErrorCategory::Server
+				| ErrorCategory::Transport
+				| ErrorCategory::Timeout
+		)
+	}
+
+	pub fn retry_after(&self) -> Option<Duration> {
+		match self {
+			Self::Api { retry_after, .. } => *retry_after,
+			_ => None,
+		}
+	}
+
+	/// Whether an interactive session can't usefully continue after this error
+	pub fn is_fatal(&self) -> bool {
+		matches!(
+			self.category(),
+			ErrorCategory::Config | ErrorCategory::Auth
+		)
+	}
+
+	pub fn exit_code(&self) -> i32 {
+		self.category().exit_code()
+	}
+
+	/// What went wrong without colors or the category, for writing to files
+	pub fn message(&self) -> String {
+		match self {
+			Self::Api { status, error, .. } => format!("{}: {}", status, error.message),
+			Self::Transport(e) => e.to_string(),
+			Self::Timeout(after) => format!("timed out after {:?}", after),
+			Self::Cancelled => String::from("cancelled"),
+			Self::OverBudget { estimate, limit } => format!(
+				"request could cost up to ${:.4}, over the ${:.4} limit",
+				estimate, limit
+			),
+			Self::UnknownPrice(model) => {
+				format!("no price known for {}, can't enforce --max-cost", model)
+			}
+			Self::Json(e) => e.to_string(),
+			Self::Config(e) => e.clone(),
+		}
+	}
+}
+
+impl fmt::Display for GptError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Api { status, error, .. } => {
+				write!(f, "{} {}", "API Error".red(), status.to_string().yellow())?;
+				let code = error.code.as_ref().map(|code| match code {
+					serde_json::Value::String(code) => code.clone(),
+					code => code.to_string(),
+				});
+				match (&error.kind, code) {
+					(Some(kind), Some(code)) => write!(f, " ({}, {})", kind.cyan(), code.cyan())?,
+					(Some(kind), None) => write!(f, " ({})", kind.cyan())?,
+					(None, Some(code)) => write!(f, " ({})", code.cyan())?,
+					(None, None) => {}
+				}
+				write!(f, "\n{}", error.message)
+			}
+			Self::Transport(e) => write!(f, "{} {}", "Connection Error".red(), e),
+			Self::Timeout(after) => write!(f, "{} after {:?}", "Timed Out".red(), after),
+			Self::Cancelled => write!(f, "{}", "Cancelled".red()),
+			Self::OverBudget { estimate, limit } => write!(
+				f,
+				"{} request could cost up to ${:.4}, over the ${:.4} limit",
+				"Over Budget".red(),
+				estimate,
+				limit
+			),
+			Self::UnknownPrice(model) => write!(
+				f,
+				"{} no price known for {}, can't enforce --max-cost",
+				"Over Budget".red(),
+				model
+			),
+			Self::Json(e) => write!(f, "{} {}", "Invalid Response".red(), e),
+			Self::Config(e) => write!(f, "{} {}", "Config Error".red(), e),
+		}
+	}
+}
+
+/// Parses a `Retry-After` header given either as seconds or as an http date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+	let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+	if let Ok(secs) = value.parse::<u64>() {
+		return Some(Duration::from_secs(secs));
+	}
+	let date = httpdate::parse_http_date(value).ok()?;
+	Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+impl Error for GptError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::Transport(e) => Some(e),
+			Self::Json(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<hyper::Error> for GptError {
+	fn from(e: hyper::Error) -> Self {
+		Self::Transport(e)
+	}
+}
+
+impl From<serde_json::Error> for GptError {
+	fn from(e: serde_json::Error) -> Self {
+		Self::Json(e)
+	}
+}
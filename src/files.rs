@@ -0,0 +1,28 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// `name` as a file in `dir` with extension `ext`. Names with an extension or
+/// more than one component are taken as paths instead, none without a dir to look in.
+pub fn named_path(dir: Option<PathBuf>, name: &str, ext: &str) -> Option<PathBuf> {
+	let path = Path::new(name);
+	if path.extension().is_some() || path.components().count() > 1 {
+		return Some(path.to_path_buf());
+	}
+	dir.map(|dir| dir.join(name).with_extension(ext))
+}
+
+/// Names of the files in `dir` with extension `ext`, sorted and without the extension
+pub fn named_files(dir: Option<PathBuf>, ext: &str) -> Vec<String> {
+	let entries = match dir.map(fs::read_dir) {
+		Some(Ok(entries)) => entries,
+		_ => return Vec::new(),
+	};
+	let mut names: Vec<String> = entries
+		.flatten()
+		.map(|entry| entry.path())
+		.filter(|path| path.extension().is_some_and(|e| e == ext))
+		.filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+		.collect();
+	names.sort();
+	names
+}
@@ -1,47 +1,534 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
-use hyper::body::Buf;
-use hyper::{header, Body, Client, Request};
-use hyper_tls::HttpsConnector;
-use rustyline::Editor;
-use serde_derive::{Deserialize, Serialize};
+use rustyline::error::ReadlineError;
 use spinners::*;
 use std::env;
 use std::error::Error;
-use tracing::{debug, Level};
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn, Level};
 
-#[derive(Debug, Parser, Serialize)]
+mod api;
+mod attachments;
+mod batch;
+mod cache;
+mod client;
+mod commands;
+mod config;
+mod conversation;
+mod error;
+mod files;
+mod output;
+mod pricing;
+mod provider;
+mod repl;
+mod retry;
+mod sessions;
+mod stream;
+mod templates;
+mod tokenizer;
+
+use api::{ChatRequest, GptRequest, GptResponse};
+use attachments::Attachment;
+use batch::{Batch, BatchDefaults};
+use cache::{CacheCommand, CacheOptions};
+use commands::SlashCommand;
+use config::Config;
+use conversation::{Conversation, Turn};
+use error::GptError;
+use output::{OutputFormat, StreamPrinter, Totals};
+use pricing::PriceTable;
+use provider::{ApiKey, Backend, Payload, Provider, ProviderKind, Transport};
+use repl::Repl;
+use retry::RetryPolicy;
+use sessions::{SavedSession, SessionsCommand};
+use templates::{TemplateOptions, TemplatesCommand};
+use tokenizer::{CountTokens, Encoding, Tokenizer};
+
+#[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
-struct GptRequest {
-	/// Prompt for GPT
-	#[clap(short = 'P', long, default_value = "")]
-	prompt: String,
-	/// Response Temperature
-	#[clap(short, long, default_value_t = 0.3)]
-	temperature: f64,
-	/// Max tokens to use
-	#[clap(short, long, default_value_t = 50)]
-	max_tokens: usize,
-	/// How Many Responses to generate
-	#[clap(short, long, default_value_t = 1)]
-	n: u8,
-	/// Stop String
-	#[clap(short, long, default_value = "")]
-	stop: String,
+struct Cli {
+	#[clap(flatten)]
+	request: GptRequest,
+	#[clap(flatten)]
+	retry: RetryPolicy,
+	#[clap(flatten)]
+	cache: CacheOptions,
+	/// Max characters of conversation history sent with each prompt
+	#[clap(short = 'c', long, default_value_t = 4000)]
+	context_budget: usize,
+	/// Config file, defaults to gpt-rust/config.toml in the user's config dir
+	#[clap(long, env = "GPT_CONFIG")]
+	config: Option<PathBuf>,
+	/// Config profile to take settings from
+	#[clap(short = 'p', long, env = "GPT_PROFILE")]
+	profile: Option<String>,
+	/// Use the chat completions endpoint
+	#[clap(long, overrides_with = "no-chat")]
+	chat: bool,
+	/// Use the completions endpoint, even if the profile sets chat
+	#[clap(long, overrides_with = "chat")]
+	no_chat: bool,
+	/// Backend to send requests to [default: openai]
+	#[clap(long, arg_enum, env = "GPT_PROVIDER")]
+	provider: Option<ProviderKind>,
+	/// Base URL of the API, defaults depend on the provider
+	#[clap(long, env = "OPENAI_BASE_URL")]
+	base_url: Option<String>,
+	/// Seconds to wait for a connection, 0 to wait forever
+	#[clap(long, default_value_t = 10)]
+	connect_timeout: u64,
+	/// Seconds to wait for a response, retries included, and between the events of a stream, 0 to wait forever
+	#[clap(long, default_value_t = 120)]
+	timeout: u64,
+	/// System prompt sent before the conversation
+	#[clap(short = 'S', long, env = "GPT_SYSTEM")]
+	system: Option<String>,
+	/// How responses are written to stdout
+	#[clap(short, long, arg_enum, default_value = "pretty")]
+	output: OutputFormat,
+	/// With --logprobs, also print a table of each token's top alternatives
+	#[clap(long, requires = "logprobs")]
+	alternatives: bool,
+	/// Continue a session saved with /save, by name or path, options given replace its settings
+	#[clap(long)]
+	resume: Option<String>,
+	/// Prompts kept in the history file, 0 turns history off [default: 1000]
+	#[clap(long, env = "GPT_HISTORY_SIZE")]
+	history_size: Option<usize>,
+	/// Json file of per-model prices overriding the shipped ones
+	#[clap(long, env = "GPT_PRICES")]
+	prices: Option<PathBuf>,
+	/// Refuse requests that could cost more than this many dollars
+	#[clap(long)]
+	max_cost: Option<f64>,
+	/// Read the prompt from a file, `-` for stdin
+	#[clap(short = 'F', long, conflicts_with = "prompt")]
+	prompt_file: Option<PathBuf>,
+	#[clap(flatten)]
+	template: TemplateOptions,
+	/// Attach a text file to the prompt, can be repeated
+	#[clap(long = "file", value_name = "PATH", multiple_occurrences = true)]
+	files: Vec<PathBuf>,
+	/// Tokens kept of each attached file, the rest is cut off
+	#[clap(long, env = "GPT_MAX_FILE_TOKENS", default_value_t = attachments::DEFAULT_MAX_FILE_TOKENS)]
+	max_file_tokens: u64,
+	/// Tiktoken vocabulary for counting tokens, defaults to the data dir
+	#[clap(long, env = "GPT_VOCAB", global = true)]
+	vocab: Option<PathBuf>,
+	#[clap(subcommand)]
+	command: Option<Command>,
 }
 
-#[derive(Debug, Deserialize)]
-struct GptChoice {
-	text: String,
-	index: u8,
-	finish_reason: String,
+#[derive(Debug, Subcommand)]
+enum Command {
+	CountTokens(CountTokens),
+	#[clap(subcommand)]
+	Sessions(SessionsCommand),
+	Batch(Batch),
+	#[clap(subcommand)]
+	Cache(CacheCommand),
+	#[clap(subcommand)]
+	Templates(TemplatesCommand),
 }
 
-#[derive(Debug, Deserialize)]
-struct GptResponse {
-	id: Option<String>,
-	model: Option<String>,
-	choices: Option<Vec<GptChoice>>,
+impl Cli {
+	/// Endpoint chosen with `--chat` or `--no-chat`, the last one given wins
+	fn chat_mode(&self) -> Option<bool> {
+		match (self.chat, self.no_chat) {
+			(true, _) => Some(true),
+			(_, true) => Some(false),
+			_ => None,
+		}
+	}
+
+	/// The prompt for a single non-interactive run, if one was given or piped in.
+	/// An empty one is refused rather than sent, since it would still be billed.
+	fn one_shot_prompt(&self) -> Result<Option<String>, GptError> {
+		let prompt = if !self.request.prompt.is_empty() {
+			self.request.prompt.clone()
+		} else {
+			match &self.prompt_file {
+				Some(path) if path.as_os_str() != "-" => fs::read_to_string(path).map_err(|e| {
+					GptError::Config(format!("Can't read {}: {}", path.display(), e))
+				})?,
+				None if io::stdin().is_terminal() => return Ok(None),
+				Some(_) | None => {
+					let mut prompt = String::new();
+					io::stdin()
+						.read_to_string(&mut prompt)
+						.map_err(|e| GptError::Config(format!("Can't read stdin: {}", e)))?;
+					prompt
+				}
+			}
+		};
+		let prompt = prompt.trim_end();
+		if prompt.trim().is_empty() {
+			return Err(GptError::Config(String::from("The prompt is empty, nothing to send")));
+		}
+		Ok(Some(prompt.to_string()))
+	}
+}
+
+/// State kept between turns of a session
+struct Session {
+	provider: Backend,
+	/// Settings every request starts from
+	request: GptRequest,
+	chat: bool,
+	system: Option<String>,
+	output: OutputFormat,
+	/// Print each token's top alternatives along with its logprob
+	alternatives: bool,
+	conversation: Conversation,
+	/// Run non-interactively, so completions prompts aren't framed as a dialogue
+	one_shot: bool,
+	prices: PriceTable,
+	max_cost: Option<f64>,
+	/// Exact token counts when a vocabulary is available, estimates otherwise
+	tokenizer: Option<Tokenizer>,
+	/// Vocabulary file given with `--vocab`, for reloading when the model changes
+	vocab: Option<PathBuf>,
+	/// Usage and cost summed over every response in this session
+	totals: Totals,
+	/// The most recent response received
+	last_response: Option<GptResponse>,
+	/// Unix time the session started
+	created: u64,
+	/// Where the session was last saved or loaded from, `/save` writes here by default
+	saved_as: Option<PathBuf>,
+	/// Files sent in front of the next prompt
+	attachments: Vec<Attachment>,
+	max_file_tokens: u64,
+}
+
+impl Session {
+	/// Sends `prompt` with the conversation so far, until it answers or Ctrl-C is pressed
+	async fn ask(&mut self, prompt: String) -> Result<&GptResponse, GptError> {
+		debug!("Sending {} previous turns as context", self.conversation.len());
+		let prompt = attachments::with_prompt(&self.attachments, &prompt);
+		let system = self.system.as_deref();
+		let chat_request;
+		let request;
+		let payload = if self.chat {
+			let messages = self.conversation.messages(system, &prompt);
+			chat_request = ChatRequest::new(messages, &self.request);
+			Payload::Chat(&chat_request)
+		} else {
+			let prompt = if self.one_shot {
+				self.conversation.one_shot_prompt(system, &prompt)
+			} else {
+				self.conversation.build_prompt(system, &prompt)
+			};
+			request = GptRequest {
+				prompt,
+				..self.request.clone()
+			};
+			Payload::Completion(&request)
+		};
+		self.request.validate(self.chat)?;
+		let prompt_tokens = self.count_tokens(&payload.prompt_text());
+		self.check_context(&payload, prompt_tokens);
+		self.check_budget(&payload, prompt_tokens)?;
+
+		let json = tokio::select! {
+			result = send(&self.provider, payload, self.request.stream, self.output) => result?,
+			_ = tokio::signal::ctrl_c() => {
+				// Dropping the request stops the spinner, let it finish drawing first
+				tokio::time::sleep(SPINNER_SETTLE).await;
+				return Err(GptError::Cancelled);
+			}
+		};
+
+		// Attachments are part of this turn now, so they stay in the context from here on
+		self.attachments.clear();
+		if let Some(choice) = json.choices.as_ref().and_then(|c| c.first()) {
+			self.conversation.push(Turn {
+				prompt,
+				response: choice.content().to_string(),
+				id: json.id.clone(),
+				model: json.model.clone().or_else(|| self.request.model.clone()),
+				usage: json.usage,
+				timestamp: sessions::now(),
+			});
+		}
+		let cost = self.record(json);
+
+		let json = self.last_response.as_ref().unwrap();
+		let streamed = self.request.stream && self.output.is_live();
+		output::print_response(
+			json,
+			self.provider.name(),
+			self.output,
+			streamed,
+			self.alternatives,
+			cost,
+			&self.totals,
+		);
+		Ok(json)
+	}
+
+	/// Applies a slash command typed at the prompt
+	fn run_command(&mut self, command: SlashCommand) -> Result<(), GptError> {
+		match command {
+			SlashCommand::Temperature(None) => show("Temperature", self.request.temperature),
+			SlashCommand::Temperature(Some(temperature)) => {
+				self.update(|request| request.temperature = Some(temperature))?
+			}
+			SlashCommand::MaxTokens(None) => show("Max Tokens", self.request.max_tokens),
+			SlashCommand::MaxTokens(Some(max_tokens)) => {
+				self.update(|request| request.max_tokens = Some(max_tokens))?
+			}
+			SlashCommand::Model(None) => show("Model", self.request.model.as_deref()),
+			SlashCommand::Model(Some(model)) => {
+				self.update(|request| request.model = Some(model))?;
+				self.reload_tokenizer();
+			}
+			SlashCommand::System(None) => show("System", self.system.as_deref()),
+			SlashCommand::System(Some(system)) => {
+				self.system = Some(system);
+				show("System", self.system.as_deref());
+			}
+			SlashCommand::Clear => {
+				self.conversation.clear();
+				println!("{}", "Conversation cleared".yellow());
+			}
+			SlashCommand::Save(name) => {
+				let path = match (name, &self.saved_as) {
+					(Some(name), _) => sessions::session_path(&name)?,
+					(None, Some(path)) => path.clone(),
+					(None, None) => sessions::session_path(&format!("session-{}", self.created))?,
+				};
+				self.snapshot().save(&path)?;
+				println!(
+					"{} {} turns to {}",
+					"Saved".green(),
+					self.conversation.len(),
+					path.display()
+				);
+				self.saved_as = Some(path);
+			}
+			SlashCommand::Load(name) => {
+				let path = sessions::session_path(&name)?;
+				self.resume(SavedSession::load(&path)?)?;
+				println!(
+					"{} {} turns from {}",
+					"Loaded".green(),
+					self.conversation.len(),
+					path.display()
+				);
+				self.saved_as = Some(path);
+			}
+			SlashCommand::Attach(None) if self.attachments.is_empty() => {
+				println!("{}", "No files attached to the next prompt".yellow())
+			}
+			SlashCommand::Attach(None) => {
+				for attachment in &self.attachments {
+					println!("{} {} tokens", attachment.name.cyan(), attachment.tokens);
+				}
+			}
+			SlashCommand::Attach(Some(path)) => {
+				let attachment = self.attach(Path::new(&path))?;
+				println!(
+					"{} {} ({} tokens) to the next prompt",
+					"Attached".green(),
+					attachment.name,
+					attachment.tokens
+				);
+			}
+			SlashCommand::Usage => {
+				println!("{} {}", "Turns:".cyan(), self.conversation.len());
+				output::print_session_totals(&self.totals);
+			}
+			SlashCommand::Help => commands::print_help(),
+			SlashCommand::Exit => {}
+		}
+		Ok(())
+	}
+
+	/// Everything needed to continue this session later
+	fn snapshot(&self) -> SavedSession {
+		SavedSession {
+			created: self.created,
+			updated: sessions::now(),
+			chat: self.chat,
+			system: self.system.clone(),
+			request: GptRequest {
+				prompt: String::new(),
+				..self.request.clone()
+			},
+			turns: self.conversation.turns().to_vec(),
+			totals: self.totals,
+		}
+	}
+
+	/// Continues a saved session, keeping how output is shown
+	fn resume(&mut self, saved: SavedSession) -> Result<(), GptError> {
+		let request = GptRequest {
+			stream: self.request.stream,
+			..saved.request
+		};
+		request.validate(saved.chat)?;
+		self.request = request;
+		self.chat = saved.chat;
+		self.system = saved.system;
+		self.conversation.restore(saved.turns);
+		self.totals = saved.totals;
+		self.created = saved.created;
+		self.reload_tokenizer();
+		Ok(())
+	}
+
+	/// Changes the request settings, keeping the old ones if the new ones are invalid
+	fn update(&mut self, change: impl FnOnce(&mut GptRequest)) -> Result<(), GptError> {
+		let mut request = self.request.clone();
+		change(&mut request);
+		request.validate(self.chat)?;
+		self.request = request;
+		println!("{}", "Updated".green());
+		Ok(())
+	}
+
+	/// Switches to the vocabulary of a newly chosen model, if it uses a different encoding
+	fn reload_tokenizer(&mut self) {
+		let encoding = Encoding::for_model(self.request.model.as_deref().unwrap_or_default());
+		if self.tokenizer.as_ref().map(Tokenizer::encoding) == Some(encoding) {
+			return;
+		}
+		self.tokenizer = match Tokenizer::open(encoding, self.vocab.as_deref()) {
+			Ok(tokenizer) => Some(tokenizer),
+			Err(e) => {
+				debug!("Estimating token counts, {}", e);
+				None
+			}
+		};
+	}
+
+	/// Reads a file to send in front of the next prompt
+	fn attach(&mut self, path: &Path) -> Result<&Attachment, GptError> {
+		let attachment = Attachment::read(path, self.max_file_tokens, |text| self.count_tokens(text))?;
+		if let Some(total) = attachment.truncated_from {
+			warn!(
+				"{} has {} tokens, only the first {} are sent",
+				attachment.name, total, attachment.tokens
+			);
+		}
+		self.attachments.push(attachment);
+		Ok(self.attachments.last().unwrap())
+	}
+
+	fn count_tokens(&self, text: &str) -> u64 {
+		match &self.tokenizer {
+			Some(tokenizer) => tokenizer.count(text) as u64,
+			None => pricing::estimate_tokens(text),
+		}
+	}
+
+	/// Warns when the prompt plus max tokens won't fit in the model's context window
+	fn check_context(&self, payload: &Payload, prompt_tokens: u64) {
+		let model = payload.model();
+		if let Some(window) = tokenizer::context_window(model) {
+			// Each choice is generated within the window on its own
+			let needed = prompt_tokens + payload.max_tokens();
+			if needed > window {
+				warn!(
+					"Prompt ({} tokens) plus max tokens needs {} tokens, over {}'s {} token window",
+					prompt_tokens, needed, model, window
+				);
+			}
+		}
+	}
+
+	/// Refuses requests whose prompt plus max tokens could cost more than `max_cost`
+	fn check_budget(&self, payload: &Payload, prompt_tokens: u64) -> Result<(), GptError> {
+		let limit = match self.max_cost {
+			Some(limit) => limit,
+			None => return Ok(()),
+		};
+		self.prices
+			.check_budget(payload.model(), prompt_tokens, payload.max_completion_tokens(), limit)
+	}
+
+	/// Adds a response to the session, returning its cost if the model's price is known
+	fn record(&mut self, response: GptResponse) -> Option<f64> {
+		// Cached responses cost nothing and used no tokens this time
+		if response.cached {
+			self.last_response = Some(response);
+			return Some(0.0);
+		}
+		let model = response.model.as_deref().or(self.request.model.as_deref());
+		let cost = response
+			.usage
+			.zip(model)
+			.and_then(|(usage, model)| self.prices.cost(model, &usage));
+		if let Some(usage) = response.usage {
+			self.totals.usage += usage;
+		}
+		self.totals.cost += cost.unwrap_or_default();
+		debug!("Session Totals: {:?}", self.totals);
+		self.last_response = Some(response);
+		cost
+	}
+}
+
+/// Prints the current value of a setting
+fn show(name: &str, value: Option<impl std::fmt::Display>) {
+	let value = value.map_or_else(|| String::from("none"), |value| value.to_string());
+	println!("{} {}", format!("{}:", name).cyan(), value.yellow());
+}
+
+/// Time for the spinner thread to draw its final frame after being stopped
+const SPINNER_SETTLE: Duration = Duration::from_millis(50);
+
+/// Seconds from the command line as a duration, where 0 means no limit
+fn seconds(secs: u64) -> Option<Duration> {
+	(secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Sends a request, showing a spinner or the streamed tokens while it runs
+async fn send(
+	provider: &Backend,
+	payload: Payload<'_>,
+	stream: bool,
+	format: OutputFormat,
+) -> Result<GptResponse, GptError> {
+	if stream {
+		let live = format.is_live();
+		let mut printer = live.then(|| StreamPrinter::new(format));
+		let result = provider
+			.stream(payload, |index, token| {
+				if let Some(printer) = &mut printer {
+					printer.token(index, token)
+				}
+			})
+			.await;
+		if let (Ok(_), Some(printer)) = (&result, printer) {
+			printer.finish();
+		}
+		result
+	} else {
+		let spinner = format
+			.is_decorated()
+			.then(|| Spinner::new(Spinners::Material, "Processing".green().to_string()));
+		let result = match payload {
+			Payload::Completion(request) => provider.complete(request).await,
+			Payload::Chat(request) => provider.chat(request).await,
+		};
+		if let Some(spinner) = spinner {
+			spinner.stop();
+			tokio::time::sleep(SPINNER_SETTLE).await;
+		}
+		result
+	}
+}
+
+/// Prints an error and exits with the code of its category
+fn exit_with(err: &GptError) -> ! {
+	eprintln!("\n{}", err);
+	std::process::exit(err.exit_code());
 }
 
 #[tokio::main]
@@ -51,86 +538,200 @@ async fn main() -> Result<(), Box<dyn Error>> {
 	} else {
 		Level::INFO
 	};
+	output::init_color();
 	tracing_subscriber::fmt()
 		.with_max_level(level)
+		.with_writer(io::stderr)
 		// .with_max_level(Level::INFO)
 		.pretty()
 		.init();
 
 	debug!("Tracing Initialized...");
-	debug!("Creating Readine Editor");
-	let mut rl = Editor::<()>::new();
-
 	debug!("Parsing args");
-	let args = GptRequest::parse();
+	let mut cli = Cli::parse();
+	match &cli.command {
+		Some(Command::CountTokens(count)) => {
+			count.run(cli.vocab.as_deref()).unwrap_or_else(|e| exit_with(&e));
+			return Ok(());
+		}
+		Some(Command::Sessions(sessions)) => {
+			sessions.run().unwrap_or_else(|e| exit_with(&e));
+			return Ok(());
+		}
+		Some(Command::Cache(cache)) => {
+			cache.run().unwrap_or_else(|e| exit_with(&e));
+			return Ok(());
+		}
+		Some(Command::Templates(templates)) => {
+			templates.run().unwrap_or_else(|e| exit_with(&e));
+			return Ok(());
+		}
+		Some(Command::Batch(_)) | None => {}
+	}
+	let template = cli.template.render().unwrap_or_else(|e| exit_with(&e));
+	if matches!(cli.command, Some(Command::Batch(_))) && (template.is_some() || !cli.files.is_empty()) {
+		exit_with(&GptError::Config(String::from(
+			"--template and --file don't apply to batch items, put the text in each item's prompt",
+		)));
+	}
+	if let Some(prompt) = template {
+		cli.request.prompt = prompt;
+	}
+	let mut args = cli.request.clone();
+	// Batches read their prompts from a file, stdin isn't a prompt for them
+	let one_shot = match cli.command {
+		Some(Command::Batch(_)) => None,
+		_ => cli.one_shot_prompt().unwrap_or_else(|e| exit_with(&e)),
+	};
+
+	// Settings resolve as command line, then env (both through clap), then profile, then defaults
+	let config = Config::load(cli.config.as_deref()).unwrap_or_else(|e| exit_with(&e));
+	let profile = config
+		.profile(cli.profile.as_deref())
+		.unwrap_or_else(|e| exit_with(&e));
+	profile.apply(&mut args);
+	args.apply_defaults();
+	let chat_mode = cli.chat_mode();
+	let chat = chat_mode.or(profile.chat).unwrap_or(false);
+	args.validate(chat).unwrap_or_else(|e| exit_with(&e));
+	let provider_kind = cli.provider.or(profile.provider).unwrap_or(ProviderKind::Openai);
+	debug!("Getting Token from {}", profile.api_key_env());
+	let key = env::var(profile.api_key_env()).ok().map(|token| ApiKey {
+		token,
+		header: profile.auth_header.clone(),
+	});
+	let base_url = cli.base_url.or(profile.base_url);
+	let system = cli.system.clone().or(profile.system);
+	let transport = Transport {
+		client: client::new_client(seconds(cli.connect_timeout)),
+		retry: cli.retry,
+		timeout: seconds(cli.timeout),
+		cache: cli.cache.cache(),
+	};
+	let provider = Backend::new(provider_kind, transport, base_url, key)
+		.unwrap_or_else(|e| exit_with(&e));
+	let mut prices = PriceTable::default();
+	prices.extend(config.prices);
+	let history_size = config.history_size;
+	if let Some(path) = &cli.prices {
+		prices
+			.load(path)
+			.map_err(|e| GptError::Config(format!("Can't read prices from {}: {}", path.display(), e)))
+			.unwrap_or_else(|e| exit_with(&e));
+	}
 
-	debug!("Setting up https connector");
-	let https = HttpsConnector::new();
+	if let Some(Command::Batch(batch)) = &cli.command {
+		let defaults = BatchDefaults {
+			request: args,
+			chat,
+			system,
+			max_cost: cli.max_cost,
+		};
+		batch
+			.run(Arc::new(provider), defaults, Arc::new(prices))
+			.await
+			.unwrap_or_else(|e| exit_with(&e));
+		return Ok(());
+	}
 
-	debug!("Setting up client");
-	let client = Client::builder().build(https);
-	let uri = "https://api.openai.com/v1/engines/text-davinci-002/completions";
+	args.model
+		.get_or_insert_with(|| provider.default_model(chat).to_string());
+	debug!("Using model {}", args.model.as_deref().unwrap_or_default());
+	let model = args.model.as_deref().unwrap_or_default();
+	let tokenizer = match Tokenizer::open(Encoding::for_model(model), cli.vocab.as_deref()) {
+		Ok(tokenizer) => Some(tokenizer),
+		Err(e) if cli.vocab.is_some() => exit_with(&GptError::Config(e.to_string())),
+		Err(e) => {
+			debug!("Estimating token counts, {}", e);
+			None
+		}
+	};
 
-	debug!("Getting Token");
-	let token: &str = &env::var("OPENAI_TOKEN").expect("Env var OPENAI_TOKEN not set");
-	let header = String::from("Bearer ") + token;
+	let mut session = Session {
+		provider,
+		request: args,
+		chat,
+		system,
+		output: cli.output,
+		alternatives: cli.alternatives,
+		conversation: Conversation::new(cli.context_budget),
+		one_shot: one_shot.is_some(),
+		prices,
+		max_cost: cli.max_cost,
+		tokenizer,
+		vocab: cli.vocab.clone(),
+		totals: Totals::default(),
+		last_response: None,
+		created: sessions::now(),
+		saved_as: None,
+		attachments: Vec::new(),
+		max_file_tokens: cli.max_file_tokens,
+	};
+	for path in &cli.files {
+		session.attach(path).unwrap_or_else(|e| exit_with(&e));
+	}
+	if let Some(name) = &cli.resume {
+		let path = sessions::session_path(name).unwrap_or_else(|e| exit_with(&e));
+		SavedSession::load(&path)
+			.and_then(|mut saved| {
+				saved.override_with(&cli.request, chat_mode, cli.system.clone())?;
+				session.resume(saved)
+			})
+			.unwrap_or_else(|e| exit_with(&e));
+		debug!("Resumed {} turns from {}", session.conversation.len(), path.display());
+		session.saved_as = Some(path);
+	}
 
-	debug!("Starting Prompt");
-	let prompt = rl.readline(&("GPT".cyan().to_string() + &" > ".green().to_string()));
-	if prompt.is_err() {
-		println!("{}", "Exiting".red());
+	if let Some(prompt) = one_shot {
+		debug!("Running non-interactively");
+		if let Err(e) = session.ask(prompt).await {
+			exit_with(&e);
+		}
 		return Ok(());
 	}
-	let prompt = prompt.unwrap();
-	let spinner = Spinner::new(Spinners::Material, "Processing".green().to_string());
-	let request = GptRequest { prompt, ..args };
-	let body = Body::from(serde_json::to_vec(&request)?);
-	debug!("Request: {:?}", body);
-
-	debug!("Creating Request");
-	let req = Request::post(uri)
-		.header(header::CONTENT_TYPE, "application/json")
-		.header("Authorization", &header)
-		.body(body)
-		.expect("Request Failed");
-
-	debug!("Sending Request");
-	let res = client.request(req).await?;
-	debug!("Got Response, Status: {}", res.status());
-	assert!(res.status().is_success());
-
-	debug!("Getting Body");
-	let body = hyper::body::aggregate(res).await?;
-	spinner.stop();
-
-	debug!("Deserializing Body");
-	let json: GptResponse = serde_json::from_reader(body.reader())?;
-	debug!("Json Received: {:#?}", json);
-
-	println!(
-		"\n\n{} from {}\n{} {}\n",
-		"Response Received".green(),
-		json
-			.model
-			.unwrap_or_else(|| String::from("OpenAI"))
-			.yellow(),
-		"Completion Id: ".cyan(),
-		json
-			.id
-			.unwrap_or_else(|| String::from("Err, Id not found"))
-			.yellow()
-	);
-
-	for choice in json.choices.expect("No Choices Received") {
-		println!(
-			"{} {}\n{}\n{} {}\n",
-			"Choice".blue(),
-			format!("#{}", choice.index + 1).magenta(),
-			choice.text,
-			"Reason:".yellow(),
-			choice.finish_reason.red()
-		);
+
+	debug!("Creating Readine Editor");
+	let history_size = cli
+		.history_size
+		.or(history_size)
+		.unwrap_or(repl::DEFAULT_HISTORY_SIZE);
+	let mut rl = Repl::new(history_size);
+	loop {
+		debug!("Starting Prompt");
+		let prompt = match rl.read() {
+			Ok(line) => line,
+			Err(ReadlineError::Interrupted) => continue,
+			Err(_) => break,
+		};
+		if prompt.trim().is_empty() {
+			continue;
+		}
+		let prompt = match SlashCommand::parse(&prompt) {
+			Some(Ok(SlashCommand::Exit)) => break,
+			Some(Ok(command)) => {
+				if let Err(e) = session.run_command(command) {
+					eprintln!("\n{}\n", e);
+				}
+				continue;
+			}
+			Some(Err(e)) => {
+				eprintln!("{} {}", "Invalid Command".red(), e);
+				continue;
+			}
+			None if prompt.starts_with("//") => prompt[1..].to_string(),
+			None => prompt,
+		};
+
+		match session.ask(prompt).await {
+			Ok(_) => {}
+			Err(e) if e.is_fatal() => exit_with(&e),
+			Err(e) => eprintln!("\n{}\n", e),
+		}
 	}
 
+	if session.output == OutputFormat::Pretty {
+		output::print_session_totals(&session.totals);
+	}
+	println!("{}", "Exiting".red());
 	Ok(())
 }
@@ -0,0 +1,359 @@
+use crate::api::{GptResponse, GptUsage, Logprobs};
+use clap::ArgEnum;
+use colored::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Write};
+
+/// How responses are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum OutputFormat {
+	/// Colored, human readable blocks
+	Pretty,
+	/// A pretty printed json document per response
+	Json,
+	/// A single line json document per response
+	Jsonl,
+	/// Only the completion text
+	Text,
+	/// The response body exactly as the API sent it
+	Raw,
+}
+
+impl OutputFormat {
+	/// Whether progress like the spinner may be drawn on stdout
+	pub fn is_decorated(self) -> bool {
+		self == Self::Pretty && io::stdout().is_terminal()
+	}
+
+	/// Whether streamed tokens are printed as they arrive
+	pub fn is_live(self) -> bool {
+		matches!(self, Self::Pretty | Self::Text)
+	}
+}
+
+/// Usage and cost summed over every response in a session
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Totals {
+	pub usage: GptUsage,
+	/// Dollars, counting only responses whose model has a known price
+	pub cost: f64,
+}
+
+/// Turns colors off when stdout is piped or redirected
+pub fn init_color() {
+	if !io::stdout().is_terminal() {
+		colored::control::set_override(false);
+	}
+}
+
+#[derive(Debug, Serialize)]
+struct OutputChoice<'a> {
+	index: u8,
+	text: &'a str,
+	finish_reason: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	logprobs: Option<&'a Logprobs>,
+}
+
+/// Structured form of a response for the json formats
+#[derive(Debug, Serialize)]
+struct OutputDocument<'a> {
+	id: Option<&'a str>,
+	model: Option<&'a str>,
+	choices: Vec<OutputChoice<'a>>,
+	usage: Option<GptUsage>,
+	/// Estimated dollars, when the model has a known price
+	cost: Option<f64>,
+	/// Usage and cost summed over every response so far in this run
+	session: Totals,
+	/// Whether the response came from the cache, so cost nothing
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	cached: bool,
+}
+
+impl<'a> OutputDocument<'a> {
+	fn new(json: &'a GptResponse, cost: Option<f64>, session: Totals) -> Self {
+		Self {
+			id: json.id.as_deref(),
+			model: json.model.as_deref(),
+			choices: json
+				.choices
+				.iter()
+				.flatten()
+				.map(|choice| OutputChoice {
+					index: choice.index,
+					text: choice.content(),
+					finish_reason: choice.finish_reason.as_deref(),
+					logprobs: choice.logprobs.as_ref(),
+				})
+				.collect(),
+			usage: json.usage,
+			cost,
+			session,
+			cached: json.cached,
+		}
+	}
+}
+
+/// Prints streamed tokens of the first choice as they arrive. Tokens of other choices come
+/// interleaved with it, so they are kept until the stream ends and printed whole by [`Self::finish`].
+#[derive(Debug)]
+pub struct StreamPrinter {
+	format: OutputFormat,
+	/// The choice being printed live
+	live: Option<u8>,
+	pending: BTreeMap<u8, String>,
+}
+
+impl StreamPrinter {
+	pub fn new(format: OutputFormat) -> Self {
+		if format == OutputFormat::Pretty {
+			println!("{}", "Streaming Response".green());
+		}
+		Self {
+			format,
+			live: None,
+			pending: BTreeMap::new(),
+		}
+	}
+
+	pub fn token(&mut self, index: u8, token: &str) {
+		match self.live {
+			Some(live) if live != index => {
+				self.pending.entry(index).or_default().push_str(token);
+				return;
+			}
+			Some(_) => {}
+			None => {
+				self.start(index, true);
+				self.live = Some(index);
+			}
+		}
+		print!("{}", token);
+		io::stdout().flush().ok();
+	}
+
+	/// Prints the choices that weren't streamed live
+	pub fn finish(self) {
+		for (index, text) in &self.pending {
+			self.start(*index, false);
+			print!("{}", text);
+		}
+		io::stdout().flush().ok();
+	}
+
+	fn start(&self, index: u8, first: bool) {
+		match self.format {
+			OutputFormat::Pretty => {
+				println!("\n{} {}", "Choice".blue(), format!("#{}", index + 1).magenta())
+			}
+			_ if !first => println!(),
+			_ => {}
+		}
+	}
+}
+
+/// Prints a response, `streamed` when its tokens were already printed by a [`StreamPrinter`].
+/// `alternatives` adds a table of each token's top alternatives when logprobs were returned.
+pub fn print_response(
+	json: &GptResponse,
+	provider: &str,
+	format: OutputFormat,
+	streamed: bool,
+	alternatives: bool,
+	cost: Option<f64>,
+	session: &Totals,
+) {
+	match format {
+		OutputFormat::Pretty => {
+			if streamed {
+				print_stream_summary(json, provider);
+			} else {
+				print_pretty(json, provider);
+			}
+			for choice in json.choices.iter().flatten() {
+				if let Some(logprobs) = &choice.logprobs {
+					println!(
+						"{} {} {}",
+						"Logprobs".blue(),
+						format!("#{}", choice.index + 1).magenta(),
+						probability_legend()
+					);
+					print_logprobs(logprobs, alternatives);
+				}
+			}
+			if json.cached {
+				println!("{} {}", "(cached)".dimmed(), "no request was sent".dimmed());
+			}
+			print_usage(json.usage.as_ref(), cost, session);
+		}
+		OutputFormat::Text if streamed => println!(),
+		OutputFormat::Text => {
+			let texts: Vec<&str> = json.choices.iter().flatten().map(|c| c.content()).collect();
+			println!("{}", texts.join("\n"));
+		}
+		OutputFormat::Json => {
+			let doc = OutputDocument::new(json, cost, *session);
+			println!("{}", serde_json::to_string_pretty(&doc).unwrap_or_default());
+		}
+		OutputFormat::Jsonl => {
+			let doc = OutputDocument::new(json, cost, *session);
+			println!("{}", serde_json::to_string(&doc).unwrap_or_default());
+		}
+		OutputFormat::Raw => println!("{}", json.raw.trim_end()),
+	}
+}
+
+fn print_pretty(json: &GptResponse, provider: &str) {
+	println!(
+		"\n\n{} from {}\n{} {}\n",
+		"Response Received".green(),
+		json.model.as_deref().unwrap_or(provider).yellow(),
+		"Completion Id: ".cyan(),
+		json.id.as_deref().unwrap_or("Err, Id not found").yellow()
+	);
+
+	for choice in json.choices.iter().flatten() {
+		println!(
+			"{} {}\n{}\n{} {}\n",
+			"Choice".blue(),
+			format!("#{}", choice.index + 1).magenta(),
+			choice.content(),
+			"Reason:".yellow(),
+			choice.finish_reason.as_deref().unwrap_or("none").red()
+		);
+	}
+}
+
+fn print_stream_summary(json: &GptResponse, provider: &str) {
+	println!(
+		"\n\n{} from {}\n{} {}",
+		"Stream Finished".green(),
+		json.model.as_deref().unwrap_or(provider).yellow(),
+		"Completion Id: ".cyan(),
+		json.id.as_deref().unwrap_or("Err, Id not found").yellow()
+	);
+	for choice in json.choices.iter().flatten() {
+		println!(
+			"{} {} {} {}",
+			"Choice".blue(),
+			format!("#{}", choice.index + 1).magenta(),
+			"Reason:".yellow(),
+			choice.finish_reason.as_deref().unwrap_or("none").red()
+		);
+	}
+	println!();
+}
+
+/// Colors `text` by how likely the model found the token, uncolored when it wasn't scored
+fn color_by_probability(text: &str, logprob: Option<f64>) -> ColoredString {
+	match logprob.map(f64::exp) {
+		Some(p) if p >= 0.9 => text.green(),
+		Some(p) if p >= 0.5 => text.yellow(),
+		Some(p) if p >= 0.1 => text.red(),
+		Some(_) => text.white().on_red(),
+		None => text.normal(),
+	}
+}
+
+fn probability_legend() -> String {
+	format!(
+		"({} {} {} {})",
+		color_by_probability("90%+", Some(0.0)),
+		color_by_probability("50%+", Some(0.6f64.ln())),
+		color_by_probability("10%+", Some(0.2f64.ln())),
+		color_by_probability("<10%", Some(f64::NEG_INFINITY))
+	)
+}
+
+fn percent(logprob: f64) -> String {
+	format!("{:.1}%", logprob.exp() * 100.0)
+}
+
+/// Prints the completion with each token colored by its probability,
+/// then optionally a row per token listing its top alternatives
+fn print_logprobs(logprobs: &Logprobs, alternatives: bool) {
+	for (token, logprob) in logprobs.tokens.iter().zip(&logprobs.token_logprobs) {
+		print!("{}", color_by_probability(token, *logprob));
+	}
+	println!("\n");
+	if !alternatives {
+		return;
+	}
+
+	println!(
+		"{:>7}  {:<24} {:>7}  {}",
+		"Offset".cyan(),
+		"Token".cyan(),
+		"Prob".cyan(),
+		"Alternatives".cyan()
+	);
+	let offsets = logprobs.offsets();
+	for (i, token) in logprobs.tokens.iter().enumerate() {
+		let logprob = logprobs.token_logprobs.get(i).copied().flatten();
+		let top = logprobs.top_logprobs.get(i).map(Vec::as_slice).unwrap_or_default();
+		let alternatives: Vec<String> = top
+			.iter()
+			.filter(|alt| &alt.token != token)
+			.map(|alt| format!("{:?} {}", alt.token, percent(alt.logprob)))
+			.collect();
+		println!(
+			"{:>7}  {} {:>7}  {}",
+			offsets[i],
+			color_by_probability(&format!("{:<24}", format!("{:?}", token)), logprob),
+			logprob.map(percent).unwrap_or_else(|| String::from("-")),
+			alternatives.join(", ")
+		);
+	}
+	println!();
+}
+
+fn print_usage(usage: Option<&GptUsage>, cost: Option<f64>, session: &Totals) {
+	match usage {
+		Some(usage) => println!(
+			"{} {} prompt + {} completion = {} {} {}",
+			"Tokens:".cyan(),
+			usage.prompt_tokens,
+			usage.completion_tokens,
+			usage.total_tokens.to_string().yellow(),
+			"Session Total:".cyan(),
+			session.usage.total_tokens.to_string().yellow()
+		),
+		None => println!(
+			"{} {} {} {}",
+			"Tokens:".cyan(),
+			"not reported".red(),
+			"Session Total:".cyan(),
+			session.usage.total_tokens.to_string().yellow()
+		),
+	}
+	match cost {
+		Some(cost) => println!(
+			"{} {} {} {}\n",
+			"Cost:".cyan(),
+			format!("${:.4}", cost).yellow(),
+			"Session Total:".cyan(),
+			format!("${:.4}", session.cost).yellow()
+		),
+		None => println!(
+			"{} {} {} {}\n",
+			"Cost:".cyan(),
+			"unknown".red(),
+			"Session Total:".cyan(),
+			format!("${:.4}", session.cost).yellow()
+		),
+	}
+}
+
+/// Prints the usage and cost totals for a whole session
+pub fn print_session_totals(totals: &Totals) {
+	println!(
+		"{} {} prompt + {} completion = {} tokens, {}",
+		"Session Usage:".cyan(),
+		totals.usage.prompt_tokens,
+		totals.usage.completion_tokens,
+		totals.usage.total_tokens.to_string().yellow(),
+		format!("${:.4}", totals.cost).yellow()
+	);
+}
@@ -0,0 +1,110 @@
+use crate::api::GptUsage;
+use crate::error::GptError;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+/// Dollars per 1000 tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Price {
+	pub prompt: f64,
+	pub completion: f64,
+}
+
+impl Price {
+	pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+		(prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion) / 1000.0
+	}
+}
+
+/// Shipped prices, checked against OpenAI's pricing page at the time of writing
+const DEFAULT_PRICES: &[(&str, f64, f64)] = &[
+	("text-davinci-003", 0.02, 0.02),
+	("text-davinci-002", 0.02, 0.02),
+	("text-curie-001", 0.002, 0.002),
+	("text-babbage-001", 0.0005, 0.0005),
+	("text-ada-001", 0.0004, 0.0004),
+	("gpt-3.5-turbo", 0.0015, 0.002),
+	("gpt-3.5-turbo-16k", 0.003, 0.004),
+	("gpt-3.5-turbo-instruct", 0.0015, 0.002),
+	("gpt-4", 0.03, 0.06),
+	("gpt-4-32k", 0.06, 0.12),
+	("gpt-4-turbo", 0.01, 0.03),
+	("gpt-4o", 0.005, 0.015),
+	("gpt-4o-mini", 0.00015, 0.0006),
+];
+
+/// Prices keyed by model name
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+	prices: HashMap<String, Price>,
+}
+
+impl Default for PriceTable {
+	fn default() -> Self {
+		let prices = DEFAULT_PRICES
+			.iter()
+			.map(|&(model, prompt, completion)| (model.to_string(), Price { prompt, completion }))
+			.collect();
+		Self { prices }
+	}
+}
+
+impl PriceTable {
+	/// Adds or replaces prices, e.g. from a config file
+	pub fn extend(&mut self, prices: HashMap<String, Price>) {
+		self.prices.extend(prices);
+	}
+
+	/// Reads a json object of `{"model": {"prompt": .., "completion": ..}}` overrides
+	pub fn load(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+		let prices: HashMap<String, Price> = serde_json::from_str(&fs::read_to_string(path)?)?;
+		self.extend(prices);
+		Ok(())
+	}
+
+	/// Price for `model`, falling back to the longest known prefix so dated snapshots
+	/// like `gpt-4-0613` use their family's price
+	pub fn get(&self, model: &str) -> Option<Price> {
+		if let Some(price) = self.prices.get(model) {
+			return Some(*price);
+		}
+		self.prices
+			.iter()
+			.filter(|(name, _)| model.starts_with(name.as_str()))
+			.max_by_key(|(name, _)| name.len())
+			.map(|(_, price)| *price)
+	}
+
+	pub fn cost(&self, model: &str, usage: &GptUsage) -> Option<f64> {
+		self.get(model)
+			.map(|price| price.cost(usage.prompt_tokens, usage.completion_tokens))
+	}
+
+	/// Refuses a request to `model` that could cost more than `limit` dollars
+	pub fn check_budget(
+		&self,
+		model: &str,
+		prompt_tokens: u64,
+		max_completion_tokens: u64,
+		limit: f64,
+	) -> Result<(), GptError> {
+		let price = self
+			.get(model)
+			.ok_or_else(|| GptError::UnknownPrice(model.to_string()))?;
+		let estimate = price.cost(prompt_tokens, max_completion_tokens);
+		debug!("Estimated worst case cost ${:.4}", estimate);
+		if estimate > limit {
+			return Err(GptError::OverBudget { estimate, limit });
+		}
+		Ok(())
+	}
+}
+
+/// Rough token count for text, used until the exact count is known
+pub fn estimate_tokens(text: &str) -> u64 {
+	(text.chars().count() as u64).div_ceil(4)
+}
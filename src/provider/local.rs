@@ -0,0 +1,51 @@
+use super::{ApiKey, HttpBackend, Payload, Provider, Transport, CHAT_PATH, COMPLETIONS_PATH};
+use crate::api::{ChatRequest, GptRequest, GptResponse};
+use crate::error::GptError;
+
+/// Ollama's default address, llama.cpp's server listens on port 8080 instead
+const BASE_URL: &str = "http://localhost:11434/v1";
+
+/// A local server exposing an OpenAI compatible `/v1` api, such as llama.cpp or Ollama
+#[derive(Debug, Clone)]
+pub struct Local {
+	http: HttpBackend,
+}
+
+impl Local {
+	/// Local servers usually run without auth, so the key is optional
+	pub fn new(
+		transport: Transport,
+		base_url: Option<String>,
+		key: Option<ApiKey>,
+	) -> Result<Self, GptError> {
+		let base_url = base_url.unwrap_or_else(|| BASE_URL.to_string());
+		Ok(Self {
+			http: HttpBackend::new(transport, base_url, key)?,
+		})
+	}
+}
+
+impl Provider for Local {
+	fn name(&self) -> &'static str {
+		"Local"
+	}
+
+	fn default_model(&self, _chat: bool) -> &'static str {
+		"llama2"
+	}
+
+	async fn complete(&self, request: &GptRequest) -> Result<GptResponse, GptError> {
+		self.http.request(COMPLETIONS_PATH, request).await
+	}
+
+	async fn chat(&self, request: &ChatRequest) -> Result<GptResponse, GptError> {
+		self.http.request(CHAT_PATH, request).await
+	}
+
+	async fn stream<F>(&self, payload: Payload<'_>, on_token: F) -> Result<GptResponse, GptError>
+	where
+		F: FnMut(u8, &str),
+	{
+		self.http.stream(payload, on_token).await
+	}
+}
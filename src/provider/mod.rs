@@ -0,0 +1,295 @@
+use crate::api::{ChatRequest, GptRequest, GptResponse, DEFAULT_MAX_TOKENS, DEFAULT_N};
+use crate::cache::Cache;
+use crate::client::{self, HttpsClient};
+use crate::error::GptError;
+use crate::retry::RetryPolicy;
+use crate::stream;
+use clap::ArgEnum;
+use hyper::header::{self, HeaderName};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::future::Future;
+use std::time::Duration;
+use tracing::debug;
+
+mod local;
+mod openai;
+
+pub use local::Local;
+pub use openai::OpenAi;
+
+pub const COMPLETIONS_PATH: &str = "completions";
+pub const CHAT_PATH: &str = "chat/completions";
+
+/// A request body for one of the completion endpoints
+#[derive(Debug, Clone, Copy)]
+pub enum Payload<'a> {
+	Completion(&'a GptRequest),
+	Chat(&'a ChatRequest),
+}
+
+impl Payload<'_> {
+	/// All prompt text the request sends, for counting its tokens
+	pub fn prompt_text(&self) -> String {
+		match self {
+			Self::Completion(request) => request.prompt.clone(),
+			Self::Chat(request) => request
+				.messages
+				.iter()
+				.map(|m| m.content.as_str())
+				.collect::<Vec<_>>()
+				.join("\n"),
+		}
+	}
+
+	pub fn model(&self) -> &str {
+		match self {
+			Self::Completion(request) => request.model.as_deref().unwrap_or_default(),
+			Self::Chat(request) => &request.model,
+		}
+	}
+
+	/// Most tokens any one choice can contain, which is what has to fit in the context window
+	pub fn max_tokens(&self) -> u64 {
+		let max_tokens = match self {
+			Self::Completion(request) => request.max_tokens,
+			Self::Chat(request) => request.max_tokens,
+		};
+		max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) as u64
+	}
+
+	/// Most tokens the response can contain over all choices
+	pub fn max_completion_tokens(&self) -> u64 {
+		match self {
+			Self::Completion(request) => request.max_completion_tokens(),
+			Self::Chat(request) => {
+				let max_tokens = request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS) as u64;
+				max_tokens * request.n.unwrap_or(DEFAULT_N) as u64
+			}
+		}
+	}
+}
+
+/// A backend able to answer completion and chat requests
+pub trait Provider {
+	/// Display name used when a response doesn't report its model
+	fn name(&self) -> &'static str;
+	/// Model used when none is given
+	fn default_model(&self, chat: bool) -> &'static str;
+	/// Sends a completions request
+	async fn complete(&self, request: &GptRequest) -> Result<GptResponse, GptError>;
+	/// Sends a chat completions request
+	async fn chat(&self, request: &ChatRequest) -> Result<GptResponse, GptError>;
+	/// Sends a streaming request, calling `on_token` with each choice index and token
+	async fn stream<F>(&self, payload: Payload<'_>, on_token: F) -> Result<GptResponse, GptError>
+	where
+		F: FnMut(u8, &str);
+}
+
+/// Which backend to send requests to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+	Openai,
+	Local,
+}
+
+/// Client, retry and timeout settings shared by every backend
+#[derive(Debug, Clone)]
+pub struct Transport {
+	pub client: HttpsClient,
+	pub retry: RetryPolicy,
+	/// Limit on getting a response, retries included, then for streams on each wait for more of it
+	pub timeout: Option<Duration>,
+	/// Where responses to deterministic requests are kept, none when caching is off
+	pub cache: Option<Cache>,
+}
+
+impl Transport {
+	async fn limit<T, F>(&self, fut: F) -> Result<T, GptError>
+	where
+		F: Future<Output = Result<T, GptError>>,
+	{
+		match self.timeout {
+			Some(limit) => tokio::time::timeout(limit, fut)
+				.await
+				.unwrap_or(Err(GptError::Timeout(limit))),
+			None => fut.await,
+		}
+	}
+}
+
+/// An API key and the header it goes in
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+	pub token: String,
+	/// Header taking the bare key, `Authorization: Bearer` is used when none is given
+	pub header: Option<String>,
+}
+
+impl ApiKey {
+	/// The header name and value to send
+	fn header(self) -> Result<(String, String), GptError> {
+		match self.header {
+			Some(name) => match HeaderName::from_bytes(name.as_bytes()) {
+				Ok(_) => Ok((name, self.token)),
+				Err(_) => Err(GptError::Config(format!("Invalid auth header name `{}`", name))),
+			},
+			None => Ok((header::AUTHORIZATION.to_string(), String::from("Bearer ") + &self.token)),
+		}
+	}
+}
+
+/// Transport shared by backends speaking the OpenAI http api
+#[derive(Debug, Clone)]
+pub struct HttpBackend {
+	transport: Transport,
+	base_url: String,
+	/// Header name and value carrying the API key
+	auth: Option<(String, String)>,
+}
+
+impl HttpBackend {
+	pub fn new(transport: Transport, base_url: String, key: Option<ApiKey>) -> Result<Self, GptError> {
+		client::check_base_url(&base_url)?;
+		Ok(Self {
+			transport,
+			base_url,
+			auth: key.map(ApiKey::header).transpose()?,
+		})
+	}
+
+	/// Posts `body` to `path` once, retrying is left to the caller
+	async fn post(
+		&self,
+		uri: &str,
+		body: &[u8],
+	) -> Result<hyper::Response<hyper::Body>, GptError> {
+		let auth = self.auth.as_ref().map(|(name, value)| (name.as_str(), value.as_str()));
+		client::post(&self.transport.client, uri, auth, body.to_vec()).await
+	}
+
+	/// The cache and key for a request whose response can be reused
+	fn cached(&self, uri: &str, body: &[u8]) -> Option<(&Cache, String)> {
+		let cache = self.transport.cache.as_ref()?;
+		Some((cache, Cache::key(uri, body)?))
+	}
+
+	pub async fn request<T: Serialize>(
+		&self,
+		path: &str,
+		body: &T,
+	) -> Result<GptResponse, GptError> {
+		let uri = client::endpoint(&self.base_url, path);
+		let body = serde_json::to_vec(body)?;
+		let cached = self.cached(&uri, &body);
+		if let Some(response) = cached.as_ref().and_then(|(cache, key)| cache.get(key)) {
+			return Ok(response);
+		}
+		debug!("Posting to {}", uri);
+		let response = self
+			.transport
+			.limit(
+				self.transport
+					.retry
+					.run(|| async { client::read_json(self.post(&uri, &body).await?).await }),
+			)
+			.await?;
+		if let Some((cache, key)) = cached {
+			cache.put(&key, &uri, &response);
+		}
+		Ok(response)
+	}
+
+	/// Only the initial request is retried, a stream failing part way through is not
+	pub async fn stream<F>(&self, payload: Payload<'_>, mut on_token: F) -> Result<GptResponse, GptError>
+	where
+		F: FnMut(u8, &str),
+	{
+		let (path, body) = match payload {
+			Payload::Completion(request) => (COMPLETIONS_PATH, serde_json::to_vec(request)?),
+			Payload::Chat(request) => (CHAT_PATH, serde_json::to_vec(request)?),
+		};
+		let uri = client::endpoint(&self.base_url, path);
+		let cached = self.cached(&uri, &body);
+		if let Some(response) = cached.as_ref().and_then(|(cache, key)| cache.get(key)) {
+			// Replay the whole text of each choice as a single token
+			for choice in response.choices.iter().flatten() {
+				on_token(choice.index, choice.content());
+			}
+			return Ok(response);
+		}
+		debug!("Streaming from {}", uri);
+		let res = self
+			.transport
+			.limit(self.transport.retry.run(|| self.post(&uri, &body)))
+			.await?;
+		let response = stream::read_stream(res, self.transport.timeout, on_token).await?;
+		if let Some((cache, key)) = cached {
+			cache.put(&key, &uri, &response);
+		}
+		Ok(response)
+	}
+}
+
+/// Any of the supported backends, chosen at runtime
+#[derive(Debug, Clone)]
+pub enum Backend {
+	OpenAi(OpenAi),
+	Local(Local),
+}
+
+impl Backend {
+	pub fn new(
+		kind: ProviderKind,
+		transport: Transport,
+		base_url: Option<String>,
+		key: Option<ApiKey>,
+	) -> Result<Self, GptError> {
+		debug!("Using {:?} provider", kind);
+		Ok(match kind {
+			ProviderKind::Openai => Self::OpenAi(OpenAi::new(transport, base_url, key)?),
+			ProviderKind::Local => Self::Local(Local::new(transport, base_url, key)?),
+		})
+	}
+}
+
+impl Provider for Backend {
+	fn name(&self) -> &'static str {
+		match self {
+			Self::OpenAi(p) => p.name(),
+			Self::Local(p) => p.name(),
+		}
+	}
+
+	fn default_model(&self, chat: bool) -> &'static str {
+		match self {
+			Self::OpenAi(p) => p.default_model(chat),
+			Self::Local(p) => p.default_model(chat),
+		}
+	}
+
+	async fn complete(&self, request: &GptRequest) -> Result<GptResponse, GptError> {
+		match self {
+			Self::OpenAi(p) => p.complete(request).await,
+			Self::Local(p) => p.complete(request).await,
+		}
+	}
+
+	async fn chat(&self, request: &ChatRequest) -> Result<GptResponse, GptError> {
+		match self {
+			Self::OpenAi(p) => p.chat(request).await,
+			Self::Local(p) => p.chat(request).await,
+		}
+	}
+
+	async fn stream<F>(&self, payload: Payload<'_>, on_token: F) -> Result<GptResponse, GptError>
+	where
+		F: FnMut(u8, &str),
+	{
+		match self {
+			Self::OpenAi(p) => p.stream(payload, on_token).await,
+			Self::Local(p) => p.stream(payload, on_token).await,
+		}
+	}
+}
@@ -0,0 +1,58 @@
+use super::{ApiKey, HttpBackend, Payload, Provider, Transport, CHAT_PATH, COMPLETIONS_PATH};
+use crate::api::{ChatRequest, GptRequest, GptResponse};
+use crate::error::GptError;
+
+const BASE_URL: &str = "https://api.openai.com/v1";
+
+/// The hosted OpenAI api, or an Azure deployment of it with the profile's `auth_header = "api-key"`
+#[derive(Debug, Clone)]
+pub struct OpenAi {
+	http: HttpBackend,
+}
+
+impl OpenAi {
+	pub fn new(
+		transport: Transport,
+		base_url: Option<String>,
+		key: Option<ApiKey>,
+	) -> Result<Self, GptError> {
+		let key = key.ok_or_else(|| {
+			GptError::Config(String::from(
+				"No API key, set OPENAI_TOKEN or the env var named by the profile's api_key_env",
+			))
+		})?;
+		let base_url = base_url.unwrap_or_else(|| BASE_URL.to_string());
+		Ok(Self {
+			http: HttpBackend::new(transport, base_url, Some(key))?,
+		})
+	}
+}
+
+impl Provider for OpenAi {
+	fn name(&self) -> &'static str {
+		"OpenAI"
+	}
+
+	fn default_model(&self, chat: bool) -> &'static str {
+		if chat {
+			"gpt-3.5-turbo"
+		} else {
+			"gpt-3.5-turbo-instruct"
+		}
+	}
+
+	async fn complete(&self, request: &GptRequest) -> Result<GptResponse, GptError> {
+		self.http.request(COMPLETIONS_PATH, request).await
+	}
+
+	async fn chat(&self, request: &ChatRequest) -> Result<GptResponse, GptError> {
+		self.http.request(CHAT_PATH, request).await
+	}
+
+	async fn stream<F>(&self, payload: Payload<'_>, on_token: F) -> Result<GptResponse, GptError>
+	where
+		F: FnMut(u8, &str),
+	{
+		self.http.stream(payload, on_token).await
+	}
+}
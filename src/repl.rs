@@ -0,0 +1,106 @@
+use crate::commands::CommandHelper;
+use colored::*;
+use rustyline::error::ReadlineError;
+use rustyline::{Config, Editor};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+/// Entries kept when neither `--history-size` nor the config file sets a size
+pub const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+/// Starts paste mode, where every line is taken as is until [`END_PASTE`]
+const PASTE: &str = "/paste";
+const END_PASTE: &str = "/end";
+
+/// `$XDG_DATA_HOME/gpt-rust/history.txt` or the platform equivalent
+pub fn history_path() -> Option<PathBuf> {
+	dirs::data_dir().map(|dir| dir.join("gpt-rust").join("history.txt"))
+}
+
+/// Line editor for the interactive prompt, with history kept between runs.
+/// Ctrl-R searches the history backwards, as in readline, and Tab completes commands.
+pub struct Repl {
+	editor: Editor<CommandHelper>,
+	/// File the history is kept in, none when history is turned off
+	history: Option<PathBuf>,
+}
+
+impl Repl {
+	/// `history_size` of 0 keeps no history at all
+	pub fn new(history_size: usize) -> Self {
+		let config = Config::builder()
+			.max_history_size(history_size)
+			.history_ignore_dups(true)
+			.history_ignore_space(true)
+			.build();
+		let mut editor = Editor::with_config(config);
+		editor.set_helper(Some(CommandHelper::new()));
+		let history = history_path().filter(|_| history_size > 0);
+		if let Some(path) = history.as_ref().filter(|path| path.exists()) {
+			debug!("Loading history from {}", path.display());
+			if let Err(e) = editor.load_history(path) {
+				warn!("Can't load history from {}: {}", path.display(), e);
+			}
+		}
+		Self { editor, history }
+	}
+
+	/// Reads one prompt. Lines ending in a backslash continue on the next line,
+	/// and `/paste` takes every line up to `/end` or Ctrl-D.
+	pub fn read(&mut self) -> Result<String, ReadlineError> {
+		let mut line = self
+			.editor
+			.readline(&("GPT".cyan().to_string() + &" > ".green().to_string()))?;
+		let text = if line.trim() == PASTE {
+			self.read_paste()?
+		} else {
+			let mut lines = Vec::new();
+			while let Some(start) = line.strip_suffix('\\') {
+				lines.push(start.to_string());
+				line = self.editor.readline(&"... > ".green().to_string())?;
+			}
+			lines.push(line);
+			lines.join("\n")
+		};
+
+		if !text.trim().is_empty() {
+			self.editor.add_history_entry(text.as_str());
+			self.save_history();
+		}
+		Ok(text)
+	}
+
+	fn read_paste(&mut self) -> Result<String, ReadlineError> {
+		println!(
+			"{} {} {}",
+			"Paste mode, finish with".yellow(),
+			END_PASTE.cyan(),
+			"or Ctrl-D".yellow()
+		);
+		let mut lines = Vec::new();
+		loop {
+			match self.editor.readline("") {
+				Ok(line) if line.trim() == END_PASTE => break,
+				Ok(line) => lines.push(line),
+				Err(ReadlineError::Eof) => break,
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(lines.join("\n"))
+	}
+
+	/// Appends new entries to the history file, so history survives the process exiting early
+	fn save_history(&mut self) {
+		let path = match &self.history {
+			Some(path) => path,
+			None => return,
+		};
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir).ok();
+		}
+		if let Err(e) = self.editor.append_history(path) {
+			warn!("Can't save history to {}: {}", path.display(), e);
+		}
+	}
+}
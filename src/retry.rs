@@ -0,0 +1,74 @@
+use crate::error::GptError;
+use clap::Args;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// When and how often to resend requests that hit rate limits or transient failures.
+// A plain comment, a doc comment here would replace the about text of the flattening command.
+#[derive(Debug, Clone, Args)]
+pub struct RetryPolicy {
+	/// Times to retry a rate limited or failed request
+	#[clap(long, default_value_t = 3)]
+	pub max_retries: u32,
+	/// Backoff before the first retry in milliseconds, doubled on each retry
+	#[clap(long, default_value_t = 500)]
+	pub retry_base_ms: u64,
+	/// Longest single backoff in seconds
+	#[clap(long, default_value_t = 30)]
+	pub retry_max_delay: u64,
+	/// Stop retrying once this many seconds have passed since the first attempt
+	#[clap(long, default_value_t = 120)]
+	pub retry_max_elapsed: u64,
+}
+
+impl RetryPolicy {
+	/// Jittered exponential backoff before retry number `retry`, starting at 0
+	fn backoff(&self, retry: u32) -> Duration {
+		let max = Duration::from_secs(self.retry_max_delay);
+		let delay = Duration::from_millis(self.retry_base_ms)
+			.saturating_mul(2u32.saturating_pow(retry))
+			.min(max);
+		// Equal jitter: wait at least half the delay, plus a random share of the rest
+		let half = delay / 2;
+		let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+		half + Duration::from_millis(jitter)
+	}
+
+	/// Runs `attempt` until it succeeds, fails with a permanent error, or the policy gives up
+	pub async fn run<T, F, Fut>(&self, mut attempt: F) -> Result<T, GptError>
+	where
+		F: FnMut() -> Fut,
+		Fut: Future<Output = Result<T, GptError>>,
+	{
+		let start = Instant::now();
+		let max_elapsed = Duration::from_secs(self.retry_max_elapsed);
+		let mut retry = 0;
+		loop {
+			let err = match attempt().await {
+				Ok(value) => return Ok(value),
+				Err(err) => err,
+			};
+			if !err.is_retryable() || retry >= self.max_retries {
+				return Err(err);
+			}
+
+			let delay = err.retry_after().unwrap_or_else(|| self.backoff(retry));
+			if start.elapsed() + delay > max_elapsed {
+				warn!("Giving up after {:?}, next retry would exceed the time limit", start.elapsed());
+				return Err(err);
+			}
+			retry += 1;
+			warn!(
+				"Request failed ({:?}), retry {}/{} in {:?}",
+				err.category(),
+				retry,
+				self.max_retries,
+				delay
+			);
+			tokio::time::sleep(delay).await;
+		}
+	}
+}
@@ -0,0 +1,226 @@
+use crate::api::GptRequest;
+use crate::conversation::Turn;
+use crate::error::GptError;
+use crate::files;
+use crate::output::Totals;
+use clap::Subcommand;
+use colored::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const EXTENSION: &str = "json";
+
+/// `$XDG_DATA_HOME/gpt-rust/sessions` or the platform equivalent
+pub fn sessions_dir() -> Option<PathBuf> {
+	dirs::data_dir().map(|dir| dir.join("gpt-rust").join("sessions"))
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since| since.as_secs())
+}
+
+fn format_time(secs: u64) -> String {
+	httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Where the session `name` is kept, see [`files::named_path`]
+pub fn session_path(name: &str) -> Result<PathBuf, GptError> {
+	files::named_path(sessions_dir(), name, EXTENSION)
+		.ok_or_else(|| GptError::Config(String::from("No data directory to keep sessions in")))
+}
+
+/// Where the session `name` is kept in the sessions dir, anything but a plain name is refused
+fn stored_session_path(name: &str) -> Result<PathBuf, GptError> {
+	let mut components = Path::new(name).components();
+	let plain = matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+	if !plain {
+		return Err(GptError::Config(format!(
+			"`{}` isn't a session name, `sessions list` shows them",
+			name
+		)));
+	}
+	sessions_dir()
+		.map(|dir| dir.join(format!("{}.{}", name, EXTENSION)))
+		.ok_or_else(|| GptError::Config(String::from("No data directory to keep sessions in")))
+}
+
+/// Names of the sessions in the sessions dir
+pub fn session_names() -> Vec<String> {
+	files::named_files(sessions_dir(), EXTENSION)
+}
+
+/// Everything needed to pick a conversation back up later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedSession {
+	/// Unix time the session started
+	pub created: u64,
+	/// Unix time the session was last saved
+	pub updated: u64,
+	pub chat: bool,
+	pub system: Option<String>,
+	/// Settings requests were sent with, without a prompt
+	pub request: GptRequest,
+	/// Every prompt and response, oldest first
+	pub turns: Vec<Turn>,
+	pub totals: Totals,
+}
+
+impl SavedSession {
+	pub fn load(path: &Path) -> Result<Self, GptError> {
+		debug!("Loading session from {}", path.display());
+		let json = fs::read_to_string(path)
+			.map_err(|e| GptError::Config(format!("Can't read {}: {}", path.display(), e)))?;
+		serde_json::from_str(&json)
+			.map_err(|e| GptError::Config(format!("Invalid session {}: {}", path.display(), e)))
+	}
+
+	pub fn save(&self, path: &Path) -> Result<(), GptError> {
+		debug!("Saving session to {}", path.display());
+		let write = || {
+			if let Some(dir) = path.parent() {
+				fs::create_dir_all(dir)?;
+			}
+			fs::write(path, serde_json::to_string_pretty(self)?)
+		};
+		write().map_err(|e| GptError::Config(format!("Can't write {}: {}", path.display(), e)))
+	}
+
+	/// Puts settings given on the command line or in the env over the saved ones
+	pub fn override_with(
+		&mut self,
+		request: &GptRequest,
+		chat: Option<bool>,
+		system: Option<String>,
+	) -> Result<(), GptError> {
+		let mut settings = serde_json::to_value(&self.request)?;
+		// Unset options aren't serialized, so only the given ones replace saved settings
+		if let (Value::Object(settings), Value::Object(given)) = (&mut settings, serde_json::to_value(request)?) {
+			for (key, value) in given.into_iter().filter(|(key, _)| key != "prompt") {
+				debug!("Overriding the saved {} setting", key);
+				settings.insert(key, value);
+			}
+		}
+		self.request = serde_json::from_value(settings)?;
+		self.chat = chat.unwrap_or(self.chat);
+		if system.is_some() {
+			self.system = system;
+		}
+		Ok(())
+	}
+
+	fn model(&self) -> &str {
+		self.request.model.as_deref().unwrap_or("default model")
+	}
+
+	fn print(&self, name: &str) {
+		println!(
+			"{} {}\n{} {}\n{} {}\n{} {} {}",
+			"Session".green(),
+			name.yellow(),
+			"Created:".cyan(),
+			format_time(self.created),
+			"Updated:".cyan(),
+			format_time(self.updated),
+			"Model:".cyan(),
+			self.model().yellow(),
+			if self.chat { "(chat)" } else { "(completions)" }
+		);
+		if let Some(system) = &self.system {
+			println!("{} {}", "System:".cyan(), system);
+		}
+		println!(
+			"{} {} tokens, {}\n",
+			"Usage:".cyan(),
+			self.totals.usage.total_tokens.to_string().yellow(),
+			format!("${:.4}", self.totals.cost).yellow()
+		);
+
+		for (i, turn) in self.turns.iter().enumerate() {
+			println!(
+				"{} {} {} {}",
+				"Turn".blue(),
+				format!("#{}", i + 1).magenta(),
+				format_time(turn.timestamp),
+				turn.id.as_deref().unwrap_or_default().dimmed()
+			);
+			println!("{} {}", "Prompt:".cyan(), turn.prompt);
+			println!("{} {}", "Response:".cyan(), turn.response);
+			if let Some(usage) = turn.usage {
+				println!(
+					"{} {} tokens from {}",
+					"Usage:".cyan(),
+					usage.total_tokens,
+					turn.model.as_deref().unwrap_or_else(|| self.model())
+				);
+			}
+			println!();
+		}
+	}
+}
+
+/// Manage the sessions saved with `/save`
+#[derive(Debug, Subcommand)]
+pub enum SessionsCommand {
+	/// List saved sessions, most recently updated first
+	List,
+	/// Print every turn of a saved session
+	Show {
+		/// Session name, as listed
+		name: String,
+	},
+	/// Delete a saved session
+	Delete {
+		/// Session name, as listed
+		name: String,
+	},
+}
+
+impl SessionsCommand {
+	pub fn run(&self) -> Result<(), GptError> {
+		match self {
+			Self::List => {
+				let mut sessions: Vec<(String, SavedSession)> = Vec::new();
+				for name in session_names() {
+					match SavedSession::load(&stored_session_path(&name)?) {
+						Ok(session) => sessions.push((name, session)),
+						Err(e) => warn!("Skipping {}: {}", name, e),
+					}
+				}
+				if sessions.is_empty() {
+					println!("{}", "No saved sessions".yellow());
+				}
+				sessions.sort_by_key(|(_, session)| std::cmp::Reverse(session.updated));
+				for (name, session) in sessions {
+					let first = session.turns.first().map_or("", |turn| turn.prompt.as_str());
+					let preview: String = first.lines().next().unwrap_or_default().chars().take(40).collect();
+					println!(
+						"{:<24} {}  {:>3} turns  {:<16} {}",
+						name.yellow(),
+						format_time(session.updated),
+						session.turns.len(),
+						session.model(),
+						preview.dimmed()
+					);
+				}
+			}
+			Self::Show { name } => SavedSession::load(&stored_session_path(name)?)?.print(name),
+			Self::Delete { name } => {
+				let path = stored_session_path(name)?;
+				// Only files that really are sessions get deleted
+				SavedSession::load(&path)?;
+				fs::remove_file(&path).map_err(|e| {
+					GptError::Config(format!("Can't delete {}: {}", path.display(), e))
+				})?;
+				println!("{} {}", "Deleted".red(), path.display());
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,237 @@
+use crate::api::{GptChoice, GptResponse, GptUsage, Logprobs};
+use crate::error::{ApiError, GptError};
+use hyper::body::HttpBody;
+use hyper::{Body, Response, StatusCode};
+use serde_derive::Deserialize;
+use std::time::Duration;
+use tracing::debug;
+
+const DONE: &str = "[DONE]";
+
+/// Incremental parser turning raw body chunks into server-sent event payloads
+#[derive(Debug, Default)]
+pub struct SseParser {
+	buffer: Vec<u8>,
+	data: Vec<String>,
+}
+
+impl SseParser {
+	/// Feeds a chunk of the body, returning the data of every event it completes
+	pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+		self.buffer.extend_from_slice(chunk);
+		let mut events = Vec::new();
+		while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = self.buffer.drain(..=end).collect();
+			let line = String::from_utf8_lossy(&line);
+			let line = line.trim_end_matches(['\r', '\n']);
+
+			if line.is_empty() {
+				if !self.data.is_empty() {
+					events.push(self.data.join("\n"));
+					self.data.clear();
+				}
+			} else if let Some(data) = line.strip_prefix("data:") {
+				self.data.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+			}
+		}
+		events
+	}
+
+	/// Returns the data of an event left unterminated when the body ended
+	pub fn finish(&mut self) -> Option<String> {
+		let line = String::from_utf8_lossy(&self.buffer);
+		if let Some(data) = line.trim_end_matches('\r').strip_prefix("data:") {
+			self.data.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+		}
+		self.buffer.clear();
+		(!self.data.is_empty()).then(|| self.data.drain(..).collect::<Vec<_>>().join("\n"))
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+	content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+	index: u8,
+	/// Token text, set by the completions endpoint
+	text: Option<String>,
+	/// Token delta, set by the chat completions endpoint
+	delta: Option<StreamDelta>,
+	finish_reason: Option<String>,
+	logprobs: Option<Logprobs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+	id: Option<String>,
+	model: Option<String>,
+	#[serde(default)]
+	choices: Vec<StreamChoice>,
+	/// Only set on the final chunk, when usage was asked for
+	usage: Option<GptUsage>,
+	error: Option<ApiError>,
+}
+
+/// Merges streamed chunks back into a complete response
+#[derive(Debug, Default)]
+struct Assembler {
+	id: Option<String>,
+	model: Option<String>,
+	choices: Vec<GptChoice>,
+	usage: Option<GptUsage>,
+	raw: String,
+}
+
+impl Assembler {
+	fn choice(&mut self, index: u8) -> &mut GptChoice {
+		if let Some(pos) = self.choices.iter().position(|c| c.index == index) {
+			return &mut self.choices[pos];
+		}
+		self.choices.push(GptChoice {
+			text: Some(String::new()),
+			message: None,
+			index,
+			finish_reason: None,
+			logprobs: None,
+		});
+		self.choices.last_mut().unwrap()
+	}
+
+	fn finish(mut self) -> GptResponse {
+		self.choices.sort_by_key(|c| c.index);
+		GptResponse {
+			id: self.id,
+			model: self.model,
+			choices: Some(self.choices),
+			usage: self.usage,
+			raw: self.raw,
+			cached: false,
+		}
+	}
+}
+
+/// Reads an event stream body, calling `on_token` with each choice index and token as it arrives
+pub async fn read_stream<F>(
+	mut res: Response<Body>,
+	idle: Option<Duration>,
+	mut on_token: F,
+) -> Result<GptResponse, GptError>
+where
+	F: FnMut(u8, &str),
+{
+	let mut parser = SseParser::default();
+	let mut assembler = Assembler::default();
+
+	debug!("Reading Event Stream");
+	'body: loop {
+		let next = res.body_mut().data();
+		let next = match idle {
+			Some(limit) => tokio::time::timeout(limit, next)
+				.await
+				.map_err(|_| GptError::Timeout(limit))?,
+			None => next.await,
+		};
+		let (events, end) = match next {
+			Some(chunk) => (parser.feed(&chunk?), false),
+			None => (parser.finish().into_iter().collect(), true),
+		};
+
+		for data in events {
+			if data == DONE {
+				debug!("Stream Done");
+				break 'body;
+			}
+			let chunk: StreamChunk = serde_json::from_str(&data)?;
+			assembler.raw += &data;
+			assembler.raw.push('\n');
+			if let Some(error) = chunk.error {
+				return Err(GptError::Api {
+					status: StatusCode::OK,
+					error,
+					retry_after: None,
+				});
+			}
+			assembler.id = assembler.id.or(chunk.id);
+			assembler.model = assembler.model.or(chunk.model);
+			assembler.usage = assembler.usage.or(chunk.usage);
+
+			for delta in chunk.choices {
+				let token = delta.text.or_else(|| delta.delta.and_then(|d| d.content));
+				let choice = assembler.choice(delta.index);
+				if let Some(token) = token.filter(|t| !t.is_empty()) {
+					choice.text.get_or_insert_with(String::new).push_str(&token);
+					on_token(delta.index, &token);
+				}
+				if let Some(logprobs) = delta.logprobs {
+					match &mut choice.logprobs {
+						Some(all) => all.extend(logprobs),
+						None => choice.logprobs = Some(logprobs),
+					}
+				}
+				if delta.finish_reason.is_some() {
+					choice.finish_reason = delta.finish_reason;
+				}
+			}
+		}
+		if end {
+			break;
+		}
+	}
+
+	Ok(assembler.finish())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn events_end_at_blank_lines() {
+		let mut parser = SseParser::default();
+		assert_eq!(parser.feed(b"data: a\n\ndata: b\n\n"), ["a", "b"]);
+		assert_eq!(parser.finish(), None);
+	}
+
+	#[test]
+	fn crlf_line_endings() {
+		let mut parser = SseParser::default();
+		assert_eq!(parser.feed(b"data: a\r\n\r\ndata: b\r\n\r\n"), ["a", "b"]);
+	}
+
+	#[test]
+	fn multi_line_data_is_joined() {
+		let mut parser = SseParser::default();
+		assert_eq!(parser.feed(b"data: one\ndata:two\n\n"), ["one\ntwo"]);
+	}
+
+	#[test]
+	fn comments_and_other_fields_are_skipped() {
+		let mut parser = SseParser::default();
+		assert_eq!(parser.feed(b": ping\nevent: message\nid: 3\ndata: x\n\n"), ["x"]);
+	}
+
+	#[test]
+	fn events_split_across_chunks() {
+		let mut parser = SseParser::default();
+		assert!(parser.feed(b"da").is_empty());
+		assert!(parser.feed(b"ta: caf\xc3").is_empty());
+		assert!(parser.feed(b"\xa9\r").is_empty());
+		assert!(parser.feed(b"\n").is_empty());
+		assert_eq!(parser.feed(b"\r\ndata: next"), ["caf\u{e9}"]);
+		assert_eq!(parser.finish(), Some(String::from("next")));
+	}
+
+	#[test]
+	fn unterminated_final_event() {
+		let mut parser = SseParser::default();
+		assert!(parser.feed(b"data: a\ndata: [DONE]").is_empty());
+		assert_eq!(parser.finish(), Some(String::from("a\n[DONE]")));
+		assert_eq!(parser.finish(), None);
+
+		assert!(parser.feed(b"data:  padded\r").is_empty());
+		assert_eq!(parser.finish(), Some(String::from(" padded")));
+	}
+}
@@ -0,0 +1,332 @@
+use crate::error::GptError;
+use crate::files;
+use clap::{Args, Subcommand};
+use colored::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+const EXTENSION: &str = "txt";
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// `$XDG_CONFIG_HOME/gpt-rust/templates` or the platform equivalent
+pub fn templates_dir() -> Option<PathBuf> {
+	dirs::config_dir().map(|dir| dir.join("gpt-rust").join("templates"))
+}
+
+fn template_path(name: &str) -> Result<PathBuf, GptError> {
+	files::named_path(templates_dir(), name, EXTENSION)
+		.ok_or_else(|| GptError::Config(String::from("No config directory to keep templates in")))
+}
+
+/// A `KEY=VALUE` template variable given on the command line
+#[derive(Debug, Clone)]
+pub struct Var {
+	pub key: String,
+	pub value: String,
+}
+
+impl FromStr for Var {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (key, value) = s
+			.split_once('=')
+			.ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))?;
+		let key = key.trim();
+		if key.is_empty() {
+			return Err(format!("missing variable name in `{}`", s));
+		}
+		Ok(Self {
+			key: key.to_string(),
+			value: value.to_string(),
+		})
+	}
+}
+
+// Prompt built from a template file
+#[derive(Debug, Clone, Args)]
+pub struct TemplateOptions {
+	/// Template to render into the prompt, by name in the templates dir or path
+	#[clap(short = 'T', long, conflicts_with_all = &["prompt", "prompt-file"])]
+	template: Option<String>,
+	/// Template variable as KEY=VALUE, overrides --vars
+	#[clap(long = "var", value_name = "KEY=VALUE", multiple_occurrences = true)]
+	vars: Vec<Var>,
+	/// Json file with an object of template variables
+	#[clap(long = "vars", value_name = "FILE")]
+	vars_file: Option<PathBuf>,
+}
+
+impl TemplateOptions {
+	/// The rendered prompt, none when no template was given
+	pub fn render(&self) -> Result<Option<String>, GptError> {
+		// Checked here rather than with clap's `requires`, which the default prompt keeps from firing
+		let name = match &self.template {
+			Some(name) => name,
+			None if self.vars.is_empty() && self.vars_file.is_none() => return Ok(None),
+			None => return Err(GptError::Config(String::from("--var and --vars need a --template"))),
+		};
+		let mut vars = match &self.vars_file {
+			Some(path) => read_vars(path)?,
+			None => HashMap::new(),
+		};
+		for var in &self.vars {
+			vars.insert(var.key.clone(), var.value.clone());
+		}
+		Template::load(name)?.render(&vars).map(Some)
+	}
+}
+
+/// Reads variables from a json object, values that aren't strings are used as their json text
+fn read_vars(path: &Path) -> Result<HashMap<String, String>, GptError> {
+	debug!("Reading template variables from {}", path.display());
+	let json = fs::read_to_string(path)
+		.map_err(|e| GptError::Config(format!("Can't read {}: {}", path.display(), e)))?;
+	let object: HashMap<String, Value> = serde_json::from_str(&json).map_err(|e| {
+		GptError::Config(format!("{} must hold a json object of variables: {}", path.display(), e))
+	})?;
+	Ok(object
+		.into_iter()
+		.map(|(key, value)| match value {
+			Value::String(value) => (key, value),
+			value => (key, value.to_string()),
+		})
+		.collect())
+}
+
+/// Piece of a template, either literal text or a `{{name}}` or `{{name:default}}` placeholder
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+	Text(&'a str),
+	Var { name: &'a str, default: Option<&'a str> },
+}
+
+/// Prompt text with `{{name}}` placeholders, `{{name:default}}` when the variable is optional
+#[derive(Debug)]
+pub struct Template {
+	name: String,
+	text: String,
+}
+
+impl Template {
+	pub fn load(name: &str) -> Result<Self, GptError> {
+		let path = template_path(name)?;
+		debug!("Loading template from {}", path.display());
+		let text = fs::read_to_string(&path)
+			.map_err(|e| GptError::Config(format!("Can't read template {}: {}", path.display(), e)))?;
+		let template = Self {
+			name: name.to_string(),
+			text: text.trim_end().to_string(),
+		};
+		template.parts()?;
+		Ok(template)
+	}
+
+	fn parts(&self) -> Result<Vec<Part<'_>>, GptError> {
+		let mut parts = Vec::new();
+		let mut rest = self.text.as_str();
+		while let Some(start) = rest.find(OPEN) {
+			parts.push(Part::Text(&rest[..start]));
+			let inner = &rest[start + OPEN.len()..];
+			let end = inner.find(CLOSE).ok_or_else(|| {
+				GptError::Config(format!("Template {} has an unclosed {}", self.name, OPEN))
+			})?;
+			let (name, default) = match inner[..end].split_once(':') {
+				Some((name, default)) => (name.trim(), Some(default)),
+				None => (inner[..end].trim(), None),
+			};
+			if name.is_empty() {
+				return Err(GptError::Config(format!(
+					"Template {} has a placeholder without a name",
+					self.name
+				)));
+			}
+			parts.push(Part::Var { name, default });
+			rest = &inner[end + CLOSE.len()..];
+		}
+		parts.push(Part::Text(rest));
+		Ok(parts)
+	}
+
+	/// Each variable once, in order of first use, with the first default given for it
+	fn variables(&self) -> Result<Vec<(&str, Option<&str>)>, GptError> {
+		let mut variables: Vec<(&str, Option<&str>)> = Vec::new();
+		for part in self.parts()? {
+			if let Part::Var { name, default } = part {
+				match variables.iter_mut().find(|(seen, _)| *seen == name) {
+					Some((_, seen_default)) => *seen_default = seen_default.or(default),
+					None => variables.push((name, default)),
+				}
+			}
+		}
+		Ok(variables)
+	}
+
+	/// Substitutes `vars`, failing with every required variable that wasn't given
+	pub fn render(&self, vars: &HashMap<String, String>) -> Result<String, GptError> {
+		let variables = self.variables()?;
+		let missing: Vec<&str> = variables
+			.iter()
+			.filter(|(name, default)| default.is_none() && !vars.contains_key(*name))
+			.map(|(name, _)| *name)
+			.collect();
+		if !missing.is_empty() {
+			return Err(GptError::Config(format!(
+				"Template {} needs {}, set with --var KEY=VALUE",
+				self.name,
+				missing.join(", ")
+			)));
+		}
+		for key in vars.keys().filter(|key| !variables.iter().any(|(name, _)| name == key)) {
+			warn!("Template {} doesn't use the variable {}", self.name, key);
+		}
+
+		let mut out = String::with_capacity(self.text.len());
+		for part in self.parts()? {
+			match part {
+				Part::Text(text) => out += text,
+				Part::Var { name, .. } => match vars.get(name) {
+					Some(value) => out += value,
+					None => {
+						let default = variables.iter().find(|(var, _)| *var == name);
+						out += default.and_then(|(_, default)| *default).unwrap_or_default();
+					}
+				},
+			}
+		}
+		Ok(out)
+	}
+}
+
+/// Inspect the prompt templates used with `--template`
+#[derive(Debug, Subcommand)]
+pub enum TemplatesCommand {
+	/// List templates in the templates dir with their variables
+	List,
+	/// Print a template and its variables
+	Show {
+		/// Template name or path
+		name: String,
+	},
+}
+
+impl TemplatesCommand {
+	pub fn run(&self) -> Result<(), GptError> {
+		match self {
+			Self::List => {
+				let names = files::named_files(templates_dir(), EXTENSION);
+				if names.is_empty() {
+					let dir = templates_dir().unwrap_or_default();
+					println!("{} {}", "No templates in".yellow(), dir.display());
+				}
+				for name in names {
+					match Template::load(&name).and_then(|template| {
+						Ok(template
+							.variables()?
+							.iter()
+							.map(|(name, _)| *name)
+							.collect::<Vec<_>>()
+							.join(", "))
+					}) {
+						Ok(variables) => println!("{:<24} {}", name.yellow(), variables.dimmed()),
+						Err(e) => warn!("Skipping {}: {}", name, e),
+					}
+				}
+			}
+			Self::Show { name } => {
+				let template = Template::load(name)?;
+				println!("{} {}", "Template".green(), name.yellow());
+				for (variable, default) in template.variables()? {
+					match default {
+						Some(default) => {
+							println!("{} {} = {:?}", "Variable:".cyan(), variable, default)
+						}
+						None => println!("{} {} {}", "Variable:".cyan(), variable, "(required)".red()),
+					}
+				}
+				println!("\n{}", template.text);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn template(text: &str) -> Template {
+		Template {
+			name: String::from("test"),
+			text: text.to_string(),
+		}
+	}
+
+	fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+		pairs
+			.iter()
+			.map(|&(key, value)| (key.to_string(), value.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn parts_split_text_and_placeholders() {
+		let template = template("Hi {{ name }}, {{greeting:good day}}!{{x:}}");
+		assert_eq!(
+			template.parts().unwrap(),
+			[
+				Part::Text("Hi "),
+				Part::Var { name: "name", default: None },
+				Part::Text(", "),
+				Part::Var { name: "greeting", default: Some("good day") },
+				Part::Text("!"),
+				Part::Var { name: "x", default: Some("") },
+				Part::Text(""),
+			]
+		);
+	}
+
+	#[test]
+	fn malformed_placeholders_are_errors() {
+		assert!(template("open {{name").parts().is_err());
+		assert!(template("empty {{ }}").parts().is_err());
+		assert!(template("empty {{:default}}").parts().is_err());
+		assert!(template("no placeholders } {").parts().is_ok());
+	}
+
+	#[test]
+	fn render_substitutes_and_falls_back_to_defaults() {
+		let template = template("{{a}} {{b:two}} {{a}} {{c:}}.");
+		assert_eq!(template.render(&vars(&[("a", "one")])).unwrap(), "one two one .");
+		assert_eq!(
+			template.render(&vars(&[("a", "1"), ("b", "2"), ("c", "3")])).unwrap(),
+			"1 2 1 3."
+		);
+	}
+
+	#[test]
+	fn default_given_anywhere_makes_a_variable_optional() {
+		let template = template("{{lang}} / {{lang:French}}");
+		assert_eq!(template.render(&HashMap::new()).unwrap(), "French / French");
+	}
+
+	#[test]
+	fn render_lists_every_missing_variable() {
+		let error = template("{{a}} {{b}} {{a}} {{c:3}}").render(&HashMap::new()).unwrap_err();
+		assert_eq!(error.message(), "Template test needs a, b, set with --var KEY=VALUE");
+	}
+
+	#[test]
+	fn vars_parse_from_key_value() {
+		let var: Var = "name = a=b".parse().unwrap();
+		assert_eq!((var.key.as_str(), var.value.as_str()), ("name", " a=b"));
+		assert!("novalue".parse::<Var>().is_err());
+		assert!("=value".parse::<Var>().is_err());
+	}
+}
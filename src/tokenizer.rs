@@ -0,0 +1,436 @@
+use crate::error::GptError;
+use clap::{ArgEnum, Args};
+use colored::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Byte pair encodings used by OpenAI models
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Encoding {
+	/// gpt-3.5-turbo, gpt-4 and the embedding models
+	#[clap(name = "cl100k_base")]
+	Cl100k,
+	/// text-davinci-002/003 and the codex models
+	#[clap(name = "p50k_base")]
+	P50k,
+	/// The original gpt-3 models, davinci, curie, babbage and ada
+	#[clap(name = "r50k_base")]
+	R50k,
+}
+
+impl Encoding {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Cl100k => "cl100k_base",
+			Self::P50k => "p50k_base",
+			Self::R50k => "r50k_base",
+		}
+	}
+
+	/// Encoding used by `model`, guessed from its family
+	pub fn for_model(model: &str) -> Self {
+		if model.starts_with("gpt-3.5") || model.starts_with("gpt-4") {
+			Self::Cl100k
+		} else if model.starts_with("text-davinci-00") || model.starts_with("code-") {
+			Self::P50k
+		} else if model.starts_with("text-") || !model.contains('-') {
+			Self::R50k
+		} else {
+			Self::Cl100k
+		}
+	}
+
+	/// Where the vocabulary is looked for when no path is given
+	pub fn default_path(self) -> Option<PathBuf> {
+		dirs::data_dir().map(|dir| {
+			dir.join("gpt-rust")
+				.join(format!("{}.tiktoken", self.name()))
+		})
+	}
+}
+
+/// Context window sizes in tokens, matched by longest prefix
+const CONTEXT_WINDOWS: &[(&str, u64)] = &[
+	("text-davinci-003", 4097),
+	("text-davinci-002", 4097),
+	("text-curie-001", 2049),
+	("text-babbage-001", 2049),
+	("text-ada-001", 2049),
+	("gpt-3.5-turbo", 4096),
+	("gpt-3.5-turbo-16k", 16385),
+	("gpt-3.5-turbo-instruct", 4096),
+	("gpt-4", 8192),
+	("gpt-4-32k", 32768),
+	("gpt-4-turbo", 128000),
+	("gpt-4o", 128000),
+];
+
+/// Names of the models with a known context window
+pub fn known_models() -> impl Iterator<Item = &'static str> {
+	CONTEXT_WINDOWS.iter().map(|&(name, _)| name)
+}
+
+/// Context window of `model` in tokens, if known
+pub fn context_window(model: &str) -> Option<u64> {
+	CONTEXT_WINDOWS
+		.iter()
+		.filter(|(name, _)| model.starts_with(name))
+		.max_by_key(|(name, _)| name.len())
+		.map(|&(_, window)| window)
+}
+
+/// An offline byte pair encoder built from a tiktoken vocabulary
+#[derive(Debug)]
+pub struct Tokenizer {
+	encoding: Encoding,
+	ranks: HashMap<Vec<u8>, u32>,
+}
+
+impl Tokenizer {
+	/// Loads a `.tiktoken` file, one base64 token and its rank per line.
+	/// Merges are implied by the ranks, lower ranks merge first.
+	pub fn load(encoding: Encoding, path: &Path) -> Result<Self, Box<dyn Error>> {
+		debug!("Loading {} vocabulary from {}", encoding.name(), path.display());
+		let file = fs::read_to_string(path)?;
+		let mut ranks = HashMap::new();
+		for (number, line) in file.lines().enumerate() {
+			if line.trim().is_empty() {
+				continue;
+			}
+			let (token, rank) = line
+				.split_once(' ')
+				.ok_or_else(|| format!("line {}: expected `<base64 token> <rank>`", number + 1))?;
+			let token = base64::decode(token).map_err(|e| format!("line {}: {}", number + 1, e))?;
+			let rank = rank
+				.trim()
+				.parse()
+				.map_err(|e| format!("line {}: {}", number + 1, e))?;
+			ranks.insert(token, rank);
+		}
+		debug!("Loaded {} tokens", ranks.len());
+		Ok(Self { encoding, ranks })
+	}
+
+	/// Loads the vocabulary for `encoding` from `path`, or from the default data dir
+	pub fn open(encoding: Encoding, path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+		let path = match path {
+			Some(path) => path.to_path_buf(),
+			None => encoding
+				.default_path()
+				.ok_or("No data directory to look for the vocabulary in")?,
+		};
+		Self::load(encoding, &path)
+			.map_err(|e| format!("Can't load vocabulary {}: {}", path.display(), e).into())
+	}
+
+	/// Token ids for `text`, special tokens are encoded as plain text
+	pub fn encode(&self, text: &str) -> Vec<u32> {
+		let mut tokens = Vec::new();
+		for piece in split(self.encoding, text) {
+			self.encode_piece(piece.as_bytes(), &mut tokens);
+		}
+		tokens
+	}
+
+	pub fn encoding(&self) -> Encoding {
+		self.encoding
+	}
+
+	pub fn count(&self, text: &str) -> usize {
+		self.encode(text).len()
+	}
+
+	/// Repeatedly merges the adjacent pair with the lowest rank until none can merge
+	fn encode_piece(&self, piece: &[u8], tokens: &mut Vec<u32>) {
+		if let Some(&rank) = self.ranks.get(piece) {
+			tokens.push(rank);
+			return;
+		}
+		// Start offsets of each part, with the end of the piece as a sentinel
+		let mut parts: Vec<usize> = (0..=piece.len()).collect();
+		loop {
+			let best = (0..parts.len().saturating_sub(2))
+				.filter_map(|i| {
+					self.ranks
+						.get(&piece[parts[i]..parts[i + 2]])
+						.map(|&rank| (rank, i))
+				})
+				.min();
+			match best {
+				Some((_, i)) => {
+					parts.remove(i + 1);
+				}
+				None => break,
+			}
+		}
+		for pair in parts.windows(2) {
+			let bytes = &piece[pair[0]..pair[1]];
+			// Every single byte has a rank in a complete vocabulary
+			tokens.push(self.ranks.get(bytes).copied().unwrap_or(u32::MAX));
+		}
+	}
+}
+
+fn is_letter(c: char) -> bool {
+	c.is_alphabetic()
+}
+
+fn is_number(c: char) -> bool {
+	c.is_numeric()
+}
+
+fn is_other(c: char) -> bool {
+	!c.is_whitespace() && !is_letter(c) && !is_number(c)
+}
+
+fn is_newline(c: char) -> bool {
+	c == '\r' || c == '\n'
+}
+
+const CONTRACTIONS: &[&str] = &["s", "t", "re", "ve", "m", "ll", "d"];
+
+/// Splits `text` into the pieces BPE runs on, following the encoding's pre-tokenizer regex
+fn split(encoding: Encoding, text: &str) -> Vec<&str> {
+	let chars: Vec<(usize, char)> = text.char_indices().collect();
+	let offset = |i: usize| chars.get(i).map_or(text.len(), |&(offset, _)| offset);
+	let mut pieces = Vec::new();
+	let mut i = 0;
+	while i < chars.len() {
+		let len = match encoding {
+			Encoding::Cl100k => match_cl100k(&chars, i),
+			Encoding::P50k | Encoding::R50k => match_p50k(&chars, i),
+		};
+		pieces.push(&text[offset(i)..offset(i + len)]);
+		i += len;
+	}
+	pieces
+}
+
+fn char_at(chars: &[(usize, char)], i: usize) -> Option<char> {
+	chars.get(i).map(|&(_, c)| c)
+}
+
+/// Length of the run of chars matching `pred` starting at `i`
+fn run(chars: &[(usize, char)], i: usize, pred: impl Fn(char) -> bool) -> usize {
+	chars[i..].iter().take_while(|&&(_, c)| pred(c)).count()
+}
+
+/// `'s|'t|'re|'ve|'m|'ll|'d`, optionally ignoring case
+fn contraction(chars: &[(usize, char)], i: usize, ignore_case: bool) -> Option<usize> {
+	if char_at(chars, i) != Some('\'') {
+		return None;
+	}
+	CONTRACTIONS
+		.iter()
+		.find(|suffix| {
+			suffix.chars().enumerate().all(|(k, expected)| {
+				char_at(chars, i + 1 + k).is_some_and(|c| {
+					if ignore_case {
+						c.to_ascii_lowercase() == expected
+					} else {
+						c == expected
+					}
+				})
+			})
+		})
+		.map(|suffix| 1 + suffix.len())
+}
+
+/// `\s+(?!\S)|\s+`, leaving the last space of a run for the word after it
+fn whitespace(chars: &[(usize, char)], i: usize) -> usize {
+	let len = run(chars, i, char::is_whitespace);
+	if len > 1 && i + len < chars.len() {
+		len - 1
+	} else {
+		len
+	}
+}
+
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`
+fn match_p50k(chars: &[(usize, char)], i: usize) -> usize {
+	if let Some(len) = contraction(chars, i, false) {
+		return len;
+	}
+	let c = chars[i].1;
+	let space = usize::from(c == ' ');
+	if let Some(next) = char_at(chars, i + space) {
+		for class in [is_letter, is_number, is_other] {
+			if class(next) {
+				return space + run(chars, i + space, class);
+			}
+		}
+	}
+	whitespace(chars, i)
+}
+
+/// `(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`
+fn match_cl100k(chars: &[(usize, char)], i: usize) -> usize {
+	if let Some(len) = contraction(chars, i, true) {
+		return len;
+	}
+	let c = chars[i].1;
+	if is_letter(c) {
+		return run(chars, i, is_letter);
+	}
+	if !is_newline(c) && !is_number(c) && char_at(chars, i + 1).is_some_and(is_letter) {
+		return 1 + run(chars, i + 1, is_letter);
+	}
+	if is_number(c) {
+		return run(chars, i, is_number).min(3);
+	}
+	let space = usize::from(c == ' ');
+	if char_at(chars, i + space).is_some_and(is_other) {
+		let len = space + run(chars, i + space, is_other);
+		return len + run(chars, i + len, is_newline);
+	}
+	// `\s*[\r\n]+` ends at the last newline in the whitespace run
+	let len = run(chars, i, char::is_whitespace);
+	if let Some(last) = (i..i + len).rev().find(|&k| is_newline(chars[k].1)) {
+		return last + 1 - i;
+	}
+	whitespace(chars, i)
+}
+
+/// Count the tokens in some text without sending it
+#[derive(Debug, Args)]
+pub struct CountTokens {
+	/// Text to count, read from stdin when missing
+	text: Option<String>,
+	/// Read the text from a file
+	#[clap(short, long, conflicts_with = "text")]
+	file: Option<PathBuf>,
+	/// Encoding to use, defaults to the one used by the model
+	#[clap(short, long, arg_enum)]
+	encoding: Option<Encoding>,
+	/// Model whose encoding and context window to use
+	#[clap(short, long, default_value = "gpt-3.5-turbo")]
+	model: String,
+}
+
+impl CountTokens {
+	pub fn run(&self, vocab: Option<&Path>) -> Result<(), GptError> {
+		let text = match (&self.text, &self.file) {
+			(Some(text), _) => text.clone(),
+			(None, Some(path)) => fs::read_to_string(path)
+				.map_err(|e| GptError::Config(format!("Can't read {}: {}", path.display(), e)))?,
+			(None, None) => {
+				let mut text = String::new();
+				io::stdin()
+					.read_to_string(&mut text)
+					.map_err(|e| GptError::Config(format!("Can't read stdin: {}", e)))?;
+				text
+			}
+		};
+		let encoding = self
+			.encoding
+			.unwrap_or_else(|| Encoding::for_model(&self.model));
+		let tokenizer =
+			Tokenizer::open(encoding, vocab).map_err(|e| GptError::Config(e.to_string()))?;
+		let count = tokenizer.count(&text);
+
+		match context_window(&self.model) {
+			Some(window) => println!(
+				"{} {} ({}, {} of {}'s {} token window)",
+				"Tokens:".cyan(),
+				count.to_string().yellow(),
+				encoding.name(),
+				format!("{:.1}%", count as f64 * 100.0 / window as f64).yellow(),
+				self.model,
+				window
+			),
+			None => println!(
+				"{} {} ({})",
+				"Tokens:".cyan(),
+				count.to_string().yellow(),
+				encoding.name()
+			),
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Expected pieces come from running the encodings' real regexes over the same text
+
+	#[test]
+	fn cl100k_splits_like_its_regex() {
+		let cases: &[(&str, &[&str])] = &[
+			("Hello world", &["Hello", " world"]),
+			("I'm HERE'S it's", &["I", "'m", " HERE", "'S", " it", "'s"]),
+			("  hello   there  ", &[" ", " hello", "  ", " there", "  "]),
+			("12345 678", &["123", "45", " ", "678"]),
+			("hello!!\n\nworld", &["hello", "!!\n\n", "world"]),
+			("a\n\n b", &["a", "\n\n", " b"]),
+			(
+				"fn main() {\n\tlet x = 1;\n}",
+				&["fn", " main", "()", " {\n", "\tlet", " x", " =", " ", "1", ";\n", "}"],
+			),
+			("naïve café 日本語", &["naïve", " café", " 日本語"]),
+			("$100.00, or 1,000", &["$", "100", ".", "00", ",", " or", " ", "1", ",", "000"]),
+		];
+		for (text, expected) in cases {
+			assert_eq!(split(Encoding::Cl100k, text), *expected, "splitting {:?}", text);
+		}
+	}
+
+	#[test]
+	fn p50k_splits_like_its_regex() {
+		let cases: &[(&str, &[&str])] = &[
+			("Hello world", &["Hello", " world"]),
+			("I'm HERE'S it's", &["I", "'m", " HERE", "'", "S", " it", "'s"]),
+			("  hello   there  ", &[" ", " hello", "  ", " there", "  "]),
+			("12345 678", &["12345", " 678"]),
+			("hello!!\n\nworld", &["hello", "!!", "\n", "\n", "world"]),
+			("a\n\n b", &["a", "\n\n", " b"]),
+			(
+				"fn main() {\n\tlet x = 1;\n}",
+				&["fn", " main", "()", " {", "\n", "\t", "let", " x", " =", " 1", ";", "\n", "}"],
+			),
+			("$100.00, or 1,000", &["$", "100", ".", "00", ",", " or", " 1", ",", "000"]),
+		];
+		for (text, expected) in cases {
+			assert_eq!(split(Encoding::P50k, text), *expected, "splitting {:?}", text);
+		}
+	}
+
+	fn tokenizer(ranks: &[(&str, u32)]) -> Tokenizer {
+		Tokenizer {
+			encoding: Encoding::Cl100k,
+			ranks: ranks
+				.iter()
+				.map(|&(token, rank)| (token.as_bytes().to_vec(), rank))
+				.collect(),
+		}
+	}
+
+	fn encode_piece(tokenizer: &Tokenizer, piece: &str) -> Vec<u32> {
+		let mut tokens = Vec::new();
+		tokenizer.encode_piece(piece.as_bytes(), &mut tokens);
+		tokens
+	}
+
+	#[test]
+	fn merges_lowest_rank_pair_first() {
+		let tokenizer = tokenizer(&[("a", 0), ("b", 1), ("c", 2), ("d", 3), ("bc", 4), ("ab", 5), ("bcd", 6)]);
+		// `bc` outranks `ab`, so `a` is left on its own and `bc` goes on to become `bcd`
+		assert_eq!(encode_piece(&tokenizer, "abcd"), [0, 6]);
+		assert_eq!(encode_piece(&tokenizer, "abc"), [0, 4]);
+		assert_eq!(encode_piece(&tokenizer, "ab"), [5]);
+	}
+
+	#[test]
+	fn whole_pieces_and_unknown_bytes() {
+		let tokenizer = tokenizer(&[("a", 0), ("b", 1), ("abab", 9)]);
+		assert_eq!(encode_piece(&tokenizer, "abab"), [9]);
+		assert_eq!(encode_piece(&tokenizer, "az"), [0, u32::MAX]);
+		// Pieces are `ab` and ` ab`, the space has no rank of its own
+		assert_eq!(tokenizer.encode("ab ab"), [0, 1, u32::MAX, 0, 1]);
+	}
+}